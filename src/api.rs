@@ -0,0 +1,278 @@
+use fastly::http::{Method, StatusCode};
+use fastly::{Body, Error, Request, Response};
+use serde::Serialize;
+
+use crate::auth::TokenSigner;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::store::{self, SessionStore};
+use crate::error::LobbyError;
+use crate::pop_strategy::PopStrategy;
+use crate::pops::Catalog;
+use crate::ranker::SessionRanker;
+use crate::{add_pings, check_token, create_session, header_u32, header_val, heartbeat, join_best_session, join_by_code, join_session, leave_session, list_sessions, optional_header, parse_create_body, parse_pings, set_ready, set_session_state, update_name, update_pop, Credentials, GameSettings, MatchRequest, Player, PlayerPing, Pop, Session, SessionCaller, SessionSettings, SessionState};
+
+// The /v2 routes do the same work as the legacy ones, but every response body is one of the
+// JSON types below instead of a hand-formatted string.
+
+#[derive(Serialize)]
+pub(crate) struct Registration {
+	player_id: u32,
+	token: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct JoinResult {
+	session_id: u32,
+	index: usize,
+	pop: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Created {
+	session_id: u32,
+	index: usize,
+	/// What other players give `/v2/join_by_code`.
+	invite_code: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PlayerView {
+	id: u32,
+	name: String,
+	index: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SessionView {
+	id: u32,
+	pop: String,
+	players: Vec<PlayerView>,
+	max_players: usize,
+	state: SessionState,
+	/// Joining needs a password.
+	password_protected: bool,
+	game: GameSettings,
+	pop_strategy: PopStrategy,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LeaveResult {
+	/// True if the player was the last one in and the session is gone.
+	session_closed: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct StateResult {
+	state: SessionState,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PopResult {
+	pop: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PingsResult {
+	/// The pings that were kept; pings to disabled pops are dropped.
+	pings: Vec<Pop>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct HeartbeatResult {
+	/// Where the session should be played, or None until someone has reported pings.
+	pop: Option<String>,
+	/// Each player's ping to `pop`; empty while `pop` is None.
+	expected_pings: Vec<PlayerPing>,
+	/// Moves to `starting` once enough players are ready.
+	state: SessionState,
+	/// Epoch milliseconds when the match started, if it has.
+	started_at: Option<u64>,
+}
+
+impl From<&Player> for PlayerView {
+	fn from(p: &Player) -> Self {
+		PlayerView {
+			id: p.id,
+			name: p.name.clone(),
+			index: p.index,
+		}
+	}
+}
+
+impl From<&Session> for SessionView {
+	fn from(s: &Session) -> Self {
+		SessionView {
+			id: s.id,
+			pop: s.pop.clone(),
+			players: s.players.iter().map(PlayerView::from).collect(),
+			max_players: s.max_players,
+			state: s.state,
+			password_protected: s.password.is_some(),
+			game: s.game.clone(),
+			pop_strategy: s.pop_strategy,
+		}
+	}
+}
+
+fn json_response<T: Serialize>(body: &T) -> Result<Response<Body>, LobbyError> {
+	let json = serde_json::to_string(body).map_err(|e| LobbyError::Internal(e.to_string()))?;
+	Ok(Response::builder()
+	.status(StatusCode::OK)
+	.header("Content-Type","application/json")
+	.header("Access-Control-Allow-Origin","*")
+	.header("Access-Control-Allow-Headers","*")
+	.header("Vary","Origin")
+	.body(Body::from(json))?)
+}
+
+pub(crate) fn handle(req: Request<Body>, store: &dyn SessionStore, clock: &dyn Clock, config: &Config, signer: &Result<TokenSigner, Error>, catalog: &Catalog) -> Result<Response<Body>, LobbyError> {
+	match (req.method(), req.uri().path()) {
+		(&Method::POST, "/v2/register") => {
+			let signer = signer.as_ref().map_err(|_| LobbyError::AuthUnavailable)?;
+			let player_id = store::allocate_player_id(store)?;
+			json_response(&Registration {
+				player_id: player_id,
+				token: signer.sign(player_id),
+			})
+		},
+		(&Method::GET, "/v2/sessions") => {
+			let sessions = list_sessions(store, clock, optional_header(&req, "state")?)?;
+			json_response(&sessions.iter().map(SessionView::from).collect::<Vec<SessionView>>())
+		},
+		(&Method::GET, "/v2/join_best_session") => {
+			let id = header_u32(&req, "id")?;
+			let signer = check_token(&req, signer, id)?;
+			let name = header_val(req.headers().get("name"));
+			let wants = MatchRequest::from_headers(&req, config, catalog)?;
+			let (session_id, index, pop) = join_best_session(store, clock, signer, &SessionRanker::from_config(config), id, name, &wants)?;
+			json_response(&JoinResult {
+				session_id: session_id,
+				index: index,
+				pop: pop,
+			})
+		},
+		(&Method::POST, "/v2/create_private_session") => {
+			let id = header_u32(&req, "id")?;
+			let signer = check_token(&req, signer, id)?;
+			let name = header_val(req.headers().get("name"));
+			let pop = header_val(req.headers().get("pop"));
+			let settings = SessionSettings::private_from_headers(&req, config)?;
+			let (session_id, code) = create_session(store, clock, signer, id, name, pop, &settings)?;
+			json_response(&Created {
+				session_id: session_id,
+				index: 0,
+				invite_code: code,
+			})
+		},
+		(&Method::POST, "/v2/create_session") => {
+			let id = header_u32(&req, "id")?;
+			let signer = check_token(&req, signer, id)?;
+			let name = header_val(req.headers().get("name")).to_string();
+			let (settings, pop) = parse_create_body(&req.into_body().into_string(), config, catalog)?;
+			let (session_id, code) = create_session(store, clock, signer, id, &name, &pop, &settings)?;
+			json_response(&Created {
+				session_id: session_id,
+				index: 0,
+				invite_code: code,
+			})
+		},
+		(&Method::POST, "/v2/join_by_code") => {
+			let player_id = header_u32(&req, "playerid")?;
+			let signer = check_token(&req, signer, player_id)?;
+			let name = header_val(req.headers().get("name"));
+			let code = header_val(req.headers().get("code"));
+			let password = header_val(req.headers().get("password"));
+			let (session_id, index, pop) = join_by_code(store, clock, signer, code, player_id, name, password)?;
+			json_response(&JoinResult {
+				session_id: session_id,
+				index: index,
+				pop: pop,
+			})
+		},
+		(&Method::GET, "/v2/join_session") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			let name = header_val(req.headers().get("name"));
+			let credentials = Credentials::from_headers(&req);
+			let (index, pop) = join_session(store, clock, caller.signer, caller.session_id, caller.player_id, name, &credentials)?;
+			json_response(&JoinResult {
+				session_id: caller.session_id,
+				index: index,
+				pop: pop,
+			})
+		},
+		(&Method::POST, "/v2/leave_session") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			json_response(&LeaveResult {
+				session_closed: leave_session(store, caller.session_id, caller.player_id)?,
+			})
+		},
+		(&Method::POST, "/v2/start_session") | (&Method::POST, "/v2/finish_session") | (&Method::POST, "/v2/update_state_in_session") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			let state = match req.uri().path() {
+				"/v2/start_session" => SessionState::Starting,
+				"/v2/finish_session" => SessionState::Finished,
+				_ => optional_header(&req, "state")?.ok_or(LobbyError::BadHeader("state"))?,
+			};
+			set_session_state(store, caller.session_id, caller.player_id, state)?;
+			json_response(&StateResult {
+				state: state,
+			})
+		},
+		(&Method::POST, "/v2/ready") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			let ready = optional_header(&req, "ready")?.unwrap_or(true);
+			json_response(&StateResult {
+				state: set_ready(store, clock, config, caller.session_id, caller.player_id, ready)?,
+			})
+		},
+		(&Method::POST, "/v2/update_name_in_session") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			let name = header_val(req.headers().get("name"));
+			json_response(&PlayerView {
+				id: caller.player_id,
+				name: name.to_string(),
+				index: update_name(store, caller.session_id, caller.player_id, name)?,
+			})
+		},
+		(&Method::POST, "/v2/update_pop_in_session") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			let pop = header_val(req.headers().get("pop"));
+			update_pop(store, catalog, caller.session_id, caller.player_id, pop)?;
+			json_response(&PopResult {
+				pop: pop.to_string(),
+			})
+		},
+		(&Method::POST, "/v2/add_pings_to_session") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			let pings = parse_pings(&req.into_body().into_string(), catalog)?;
+			add_pings(store, caller.session_id, caller.player_id, &pings)?;
+			json_response(&PingsResult {
+				pings: pings,
+			})
+		},
+		// the whole catalog, disabled pops included
+		(&Method::GET, "/v2/get_pops") => json_response(catalog),
+		(&Method::POST, "/v2/heartbeat") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			match heartbeat(store, clock, config, catalog, caller.session_id, caller.player_id)? {
+				Some(status) => {
+					let (pop, expected_pings) = match status.pop {
+						Ok(placement) => (Some(placement.pop), placement.pings),
+						Err(_) => (None, Vec::new()),
+					};
+					json_response(&HeartbeatResult {
+						pop: pop,
+						expected_pings: expected_pings,
+						state: status.state,
+						started_at: status.started_at,
+					})
+				},
+				None => Err(LobbyError::SessionNotFound),
+			}
+		},
+		_ => Ok(Response::builder()
+			.status(StatusCode::NOT_FOUND)
+			.body(Body::from("The page you requested could not be found"))?),
+	}
+}
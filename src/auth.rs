@@ -0,0 +1,109 @@
+use fastly::Error;
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::config::Config;
+use crate::error::LobbyError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues and checks player tokens: the hex HMAC-SHA256 of the player id under a secret
+/// from the `token_secret` setting. Holding the token for an id is what lets a client act
+/// as that player.
+pub(crate) struct TokenSigner {
+	secret: Vec<u8>,
+}
+
+impl TokenSigner {
+	pub(crate) fn new(secret: &str) -> Self {
+		TokenSigner {
+			secret: secret.as_bytes().to_vec(),
+		}
+	}
+
+	pub(crate) fn from_config(config: &Config) -> Result<Self, Error> {
+		match config.get("token_secret") {
+			Some(secret) if !secret.is_empty() => Ok(TokenSigner::new(&secret)),
+			_ => Err(Error::msg("no token_secret configured")),
+		}
+	}
+
+	fn mac(&self, message: &str) -> HmacSha256 {
+		// HMAC accepts keys of any length
+		let mut mac = HmacSha256::new_varkey(&self.secret).unwrap();
+		mac.update(message.as_bytes());
+		mac
+	}
+
+	pub(crate) fn sign(&self, player_id: u32) -> String {
+		to_hex(&self.mac(&player_id.to_string()).finalize().into_bytes())
+	}
+
+	pub(crate) fn verify(&self, player_id: u32, token: &str) -> bool {
+		match from_hex(token) {
+			Some(tag) => self.mac(&player_id.to_string()).verify(&tag).is_ok(),
+			None => false,
+		}
+	}
+
+	/// The HMAC of `message` under the same secret, for anything else that mustn't be
+	/// guessable from what it's made of. `message` must not be all digits, or it could pass
+	/// for a player's token.
+	pub(crate) fn tag(&self, message: &str) -> Vec<u8> {
+		self.mac(message).finalize().into_bytes().to_vec()
+	}
+}
+
+/// Checks that `token` was issued for `player_id`, and hands back the signer for anything
+/// else the request needs it for. Without a configured secret nothing verifies.
+pub(crate) fn authorize<'a>(signer: &'a Result<TokenSigner, Error>, player_id: u32, token: &str) -> Result<&'a TokenSigner, LobbyError> {
+	match signer {
+		Ok(signer) if signer.verify(player_id, token) => Ok(signer),
+		Ok(_) => Err(LobbyError::BadToken),
+		Err(_) => Err(LobbyError::AuthUnavailable),
+	}
+}
+
+/// A session password as stored: the hex HMAC-SHA256 of a per-session salt and the password
+/// under the token secret. Without the secret a leaked store gives nothing to guess against,
+/// and the salt keeps equal passwords on two sessions from looking alike.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct PasswordHash {
+	salt: String,
+	hash: String,
+}
+
+impl PasswordHash {
+	/// `seed` only has to differ between sessions; the session id and creation time will do.
+	pub(crate) fn new(signer: &TokenSigner, password: &str, seed: &str) -> Self {
+		let salt = to_hex(&signer.tag(&format!("salt:{}", seed))[..16]);
+		let hash = to_hex(&Self::mac(signer, &salt, password).finalize().into_bytes());
+		PasswordHash {
+			salt: salt,
+			hash: hash,
+		}
+	}
+
+	fn mac(signer: &TokenSigner, salt: &str, password: &str) -> HmacSha256 {
+		signer.mac(&format!("password:{}:{}", salt, password))
+	}
+
+	pub(crate) fn matches(&self, signer: &TokenSigner, password: &str) -> bool {
+		match from_hex(&self.hash) {
+			Some(tag) => Self::mac(signer, &self.salt, password).verify(&tag).is_ok(),
+			None => false,
+		}
+	}
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+	if hex.len() % 2 != 0 || !hex.is_ascii() {
+		return None;
+	}
+	(0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
@@ -0,0 +1,30 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of wall-clock time as Unix epoch milliseconds. Heartbeats are compared across
+/// wasm instances and POPs, so they have to come from a clock that means the same thing
+/// everywhere, and tests can swap in a `FakeClock`.
+pub(crate) trait Clock {
+	fn now_millis(&self) -> u64;
+}
+
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now_millis(&self) -> u64 {
+		match SystemTime::now().duration_since(UNIX_EPOCH) {
+			Ok(d) => d.as_millis() as u64,
+			_ => 0,
+		}
+	}
+}
+
+/// A clock stuck at a given time.
+#[cfg(test)]
+pub(crate) struct FakeClock(pub(crate) u64);
+
+#[cfg(test)]
+impl Clock for FakeClock {
+	fn now_millis(&self) -> u64 {
+		self.0
+	}
+}
@@ -0,0 +1,37 @@
+use fastly::Dictionary;
+use std::env;
+use std::str::FromStr;
+
+/// The service's settings for one request. `LOBBY_<KEY>` in the environment wins so local
+/// runs don't need a dictionary; otherwise a setting comes from the edge dictionary named
+/// by `LOBBY_CONFIG_DICTIONARY` when the service was built (see the README).
+pub(crate) struct Config {
+	dictionary: Option<Dictionary>,
+}
+
+impl Config {
+	/// Opens the dictionary, once per request. Fastly can't open a dictionary that isn't
+	/// attached to the service without panicking, so a build that names none never tries,
+	/// and every setting is left to the environment or its default.
+	pub(crate) fn open() -> Self {
+		Config {
+			dictionary: option_env!("LOBBY_CONFIG_DICTIONARY").filter(|name| !name.is_empty()).map(Dictionary::open),
+		}
+	}
+
+	/// Looks up a service setting.
+	pub(crate) fn get(&self, key: &str) -> Option<String> {
+		if let Ok(value) = env::var(format!("LOBBY_{}", key.to_uppercase())) {
+			return Some(value);
+		}
+		self.dictionary.as_ref().and_then(|dictionary| dictionary.get(key))
+	}
+
+	/// A setting parsed as `T`, or `default` when it's missing or doesn't parse.
+	pub(crate) fn get_parsed<T: FromStr>(&self, key: &str, default: T) -> T {
+		match self.get(key).map(|v| v.parse::<T>()) {
+			Some(Ok(v)) => v,
+			_ => default,
+		}
+	}
+}
@@ -0,0 +1,121 @@
+use fastly::http::StatusCode;
+use fastly::{Body, Error, Response};
+use serde::Serialize;
+use std::fmt;
+
+/// Everything that can go wrong handling a lobby request, each with its own status code and
+/// a stable `code` string clients can match on.
+#[derive(Debug)]
+pub(crate) enum LobbyError {
+	/// A required header was missing or didn't parse.
+	BadHeader(&'static str),
+	/// The request body didn't parse.
+	BadBody(String),
+	/// The token doesn't match the player id.
+	BadToken,
+	SessionNotFound,
+	SessionFull,
+	/// The session has moved past the lobby; carries its state.
+	SessionNotJoinable(&'static str),
+	/// The session has a password and the one given was wrong or missing.
+	WrongPassword,
+	/// The player isn't in the session they're trying to act on.
+	NotInSession,
+	/// The session can't go from the first state to the second.
+	InvalidTransition(&'static str, &'static str),
+	/// No token secret is configured, so players can't be registered or verified.
+	AuthUnavailable,
+	/// The session store couldn't be read or written.
+	StoreUnavailable(String),
+	/// A bug on our side, such as a response that couldn't be built.
+	Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+	error: &'a str,
+	message: String,
+}
+
+impl LobbyError {
+	pub(crate) fn status(&self) -> StatusCode {
+		match self {
+			LobbyError::BadHeader(_) => StatusCode::BAD_REQUEST,
+			LobbyError::BadBody(_) => StatusCode::BAD_REQUEST,
+			LobbyError::BadToken => StatusCode::FORBIDDEN,
+			LobbyError::SessionNotFound => StatusCode::NOT_FOUND,
+			LobbyError::SessionFull => StatusCode::CONFLICT,
+			LobbyError::SessionNotJoinable(_) => StatusCode::CONFLICT,
+			LobbyError::WrongPassword => StatusCode::FORBIDDEN,
+			LobbyError::NotInSession => StatusCode::FORBIDDEN,
+			LobbyError::InvalidTransition(_, _) => StatusCode::CONFLICT,
+			LobbyError::AuthUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+			LobbyError::StoreUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+			LobbyError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+		}
+	}
+
+	pub(crate) fn code(&self) -> &'static str {
+		match self {
+			LobbyError::BadHeader(_) => "bad_header",
+			LobbyError::BadBody(_) => "bad_body",
+			LobbyError::BadToken => "bad_token",
+			LobbyError::SessionNotFound => "session_not_found",
+			LobbyError::SessionFull => "session_full",
+			LobbyError::SessionNotJoinable(_) => "session_not_joinable",
+			LobbyError::WrongPassword => "wrong_password",
+			LobbyError::NotInSession => "not_in_session",
+			LobbyError::InvalidTransition(_, _) => "invalid_transition",
+			LobbyError::AuthUnavailable => "auth_unavailable",
+			LobbyError::StoreUnavailable(_) => "store_unavailable",
+			LobbyError::Internal(_) => "internal",
+		}
+	}
+
+	/// `{"error": <code>, "message": <description>}` with the matching status.
+	pub(crate) fn to_response(&self) -> Result<Response<Body>, Error> {
+		let body = ErrorBody {
+			error: self.code(),
+			message: self.to_string(),
+		};
+		Ok(Response::builder()
+		.status(self.status())
+		.header("Content-Type","application/json")
+		.header("Access-Control-Allow-Origin","*")
+		.header("Access-Control-Allow-Headers","*")
+		.header("Vary","Origin")
+		.body(Body::from(serde_json::to_string(&body)?))?)
+	}
+}
+
+impl fmt::Display for LobbyError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			LobbyError::BadHeader(name) => write!(f, "missing or invalid {} header", name),
+			LobbyError::BadBody(e) => write!(f, "invalid request body: {}", e),
+			LobbyError::BadToken => write!(f, "token does not match player"),
+			LobbyError::SessionNotFound => write!(f, "no such session"),
+			LobbyError::SessionFull => write!(f, "session is full"),
+			LobbyError::SessionNotJoinable(state) => write!(f, "session is {} and not taking players", state),
+			LobbyError::WrongPassword => write!(f, "wrong or missing session password"),
+			LobbyError::NotInSession => write!(f, "player is not in this session"),
+			LobbyError::InvalidTransition(from, to) => write!(f, "session can't go from {} to {}", from, to),
+			LobbyError::AuthUnavailable => write!(f, "player tokens are not configured"),
+			LobbyError::StoreUnavailable(e) => write!(f, "session store unavailable: {}", e),
+			LobbyError::Internal(e) => write!(f, "internal error: {}", e),
+		}
+	}
+}
+
+// the lobby only gets fastly::Errors from the store, so they mean we couldn't reach our data
+impl From<Error> for LobbyError {
+	fn from(e: Error) -> Self {
+		LobbyError::StoreUnavailable(e.to_string())
+	}
+}
+
+impl From<fastly::http::Error> for LobbyError {
+	fn from(e: fastly::http::Error) -> Self {
+		LobbyError::Internal(e.to_string())
+	}
+}
@@ -0,0 +1,24 @@
+use crate::auth::TokenSigner;
+
+// no 0/O or 1/I, so codes read out on stream or over voice chat can't be mistyped
+const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LEN: usize = 6;
+
+/// A short invite code for a session, cut from the HMAC of its id and creation time under the
+/// token secret, so nobody can work out a private session's code from its id. `attempt` gives
+/// another code for the same session when the first is already in use. 32^6 codes is plenty
+/// for the number of sessions open at once.
+pub(crate) fn generate(signer: &TokenSigner, session_id: u32, created_at: u64, attempt: u32) -> String {
+	signer.tag(&format!("invite:{}:{}:{}", session_id, created_at, attempt)).iter()
+		.take(CODE_LEN)
+		.map(|b| ALPHABET[(*b as usize) % ALPHABET.len()] as char)
+		.collect()
+}
+
+/// What the player typed, uppercased with spaces and dashes dropped.
+pub(crate) fn normalize(code: &str) -> String {
+	code.chars()
+		.filter(|c| c.is_ascii_alphanumeric())
+		.map(|c| c.to_ascii_uppercase())
+		.collect()
+}
@@ -1,65 +1,64 @@
 /// Default Compute@Edge template program.
 
 use fastly::http::{Method, StatusCode};
-use fastly::{Body, Error, Request, RequestExt, Response, ResponseExt};
+use fastly::{Body, Error, Request, Response, ResponseExt};
 use fastly::http::header::HeaderValue;
 use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
 use core::cmp::Ordering::Equal;
 
 use serde::{Serialize,Deserialize};
-extern crate serde_millis;
 
-use std::time::{Duration,Instant};
+mod api;
+mod auth;
+mod clock;
+mod config;
+mod error;
+mod invite;
+mod pop_strategy;
+mod pops;
+mod ranker;
+mod schema;
+mod store;
+use clock::Clock;
+use config::Config;
+use error::LobbyError;
+use pop_strategy::PopStrategy;
+use store::SessionStore;
 
+// sessions hold this many players unless their creator asks for something else
+const DEFAULT_MAX_PLAYERS: usize = 4;
+const MIN_PLAYERS: usize = 2;
+
+// players that haven't sent a heartbeat for this long are dropped from their session
+const HEARTBEAT_TIMEOUT_MS: u64 = 60 * 1000;
+
+// /join_best_session skips sessions the player would have more latency than this to
+const DEFAULT_MAX_JOIN_LATENCY_MS: u32 = 150;
+// a heartbeat only moves a session to a pop that scores this much better than its current
+// one, and only once it has kept doing so for this long
+const DEFAULT_POP_SWITCH_MARGIN_MS: f32 = 10.0;
+const DEFAULT_POP_SWITCH_HOLD_MS: u64 = 15 * 1000;
+
+// anything slower than this isn't a ping worth playing on, and is more likely a bad report
+const MAX_PING_MS: u32 = 10_000;
+
+// how many ranked sessions /join_best_session tries before it gives up and creates one
+const MAX_JOIN_ATTEMPTS: usize = 3;
+
+// how many invite codes a new session tries before giving up on finding a free one
+const MAX_INVITE_ATTEMPTS: u32 = 10;
+
+/// The `/ping` reply: which pop answered and when, by its clock.
 #[derive(Serialize)]
-struct StaticPop {
-	name: &'static str,
-	ip: &'static str,
-}
-
-const POPS: &'static [&'static StaticPop] = &[
-	&StaticPop{name: "HKG", ip: "151.101.77.51"},
-	&StaticPop{name: "IAH", ip: "151.101.181.51"},
-	&StaticPop{name: "JAX", ip: "199.232.1.51"},
-	&StaticPop{name: "JNB", ip: "151.101.173.51"},
-	&StaticPop{name: "MCI", ip: "199.232.73.51"},
-	&StaticPop{name: "LCY", ip: "151.101.17.51"},
-	&StaticPop{name: "LON", ip: "199.232.57.51"},
-	&StaticPop{name: "LHR", ip: "151.101.61.51"},
-	&StaticPop{name: "BUR", ip: "151.101.197.51"},
-	&StaticPop{name: "LGB", ip: "151.101.25.51"},
-	&StaticPop{name: "MAD", ip: "151.101.133.51"},
-	&StaticPop{name: "MAN", ip: "199.232.53.51"},
-	&StaticPop{name: "MRS", ip: "199.232.81.51"},
-	&StaticPop{name: "MEL", ip: "151.101.81.51"},
-	&StaticPop{name: "MIA", ip: "151.101.5.51"},
-	&StaticPop{name: "MSP", ip: "151.101.149.51"},
-	&StaticPop{name: "STP", ip: "199.232.29.51"},
-	&StaticPop{name: "YUL", ip: "151.101.137.51"},
-	&StaticPop{name: "BOM", ip: "151.101.153.51"},
-	&StaticPop{name: "LGA", ip: "199.232.37.51"},
-	&StaticPop{name: "EWR", ip: "151.101.209.51"},
-	&StaticPop{name: "ITM", ip: "151.101.89.51"},
-	&StaticPop{name: "OSL", ip: "151.101.237.51"},
-	&StaticPop{name: "PAO", ip: "151.101.189.51"},
-	&StaticPop{name: "CDG", ip: "151.101.121.51"},
-	&StaticPop{name: "GIG", ip: "151.101.177.51"},
-	&StaticPop{name: "SJC", ip: "151.101.41.51"},
-	&StaticPop{name: "SCL", ip: "151.101.221.51"},
-	&StaticPop{name: "GRU", ip: "151.101.93.51"},
-	&StaticPop{name: "SEA", ip: "151.101.53.51"},
-	&StaticPop{name: "SIN", ip: "151.101.9.51"},
-	&StaticPop{name: "STL", ip: "199.232.69.51"},
-	&StaticPop{name: "BMA", ip: "151.101.85.51"},
-	&StaticPop{name: "SYD", ip: "151.101.29.51"},
-	&StaticPop{name: "TYO", ip: "151.101.109.51"},
-	&StaticPop{name: "HND", ip: "151.101.229.51"},
-	&StaticPop{name: "YYZ", ip: "151.101.125.51"},
-	&StaticPop{name: "YVR", ip: "151.101.213.51"},
-	&StaticPop{name: "VIE", ip: "199.232.17.51"},
-];
+struct PingReply {
+	pop: String,
+	// unix epoch milliseconds
+	server_time: u64,
+}
 
-#[derive(Serialize,Deserialize)]
+#[derive(Clone,Serialize,Deserialize)]
 struct Pop {
 	name: String,
 	ping: u32,
@@ -70,201 +69,978 @@ struct Player {
 	name: String,
 	id: u32,
 	index: usize,
-	#[serde(with = "serde_millis")]
-	last_heartbeat: Instant,
+	// unix epoch milliseconds
+	last_heartbeat: u64,
 	pops: Vec<Pop>,
+	ready: bool,
 }
 
 #[derive(Serialize,Deserialize)]
 struct Session {
 	id: u32,
 	pop: String,
-	players: Vec<Player>
+	players: Vec<Player>,
+	max_players: usize,
+	state: SessionState,
+	// unix epoch milliseconds when enough players were ready, None while in the lobby
+	started_at: Option<u64>,
+	// private sessions aren't listed or matched; players get in with the invite code
+	private: bool,
+	invite_code: String,
+	// None for sessions anyone can join
+	password: Option<auth::PasswordHash>,
+	game: GameSettings,
+	// unix epoch milliseconds
+	created_at: u64,
+	// how heartbeats pick the pop from everyone's pings
+	pop_strategy: PopStrategy,
+	// a pop that has been beating `pop` on recent heartbeats
+	pop_challenger: Option<PopChallenger>,
 }
 
-const KV_LOCAL: &str = "kvlocal";
-const KV_GLOBAL: &str = "kvglobal";
+#[derive(Serialize,Deserialize)]
+struct PopChallenger {
+	pop: String,
+	// unix epoch milliseconds of the first heartbeat in its current run of winning by the
+	// switch margin
+	since: u64,
+}
 
-fn header_val(header: Option<&HeaderValue>) -> &str {
-	match header {
-		Some(h) => h.to_str().unwrap_or(""),
-		None => "",
+/// What the creator of a session gets to choose.
+struct SessionSettings {
+	max_players: usize,
+	private: bool,
+	password: Option<String>,
+	game: GameSettings,
+	pop_strategy: PopStrategy,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum GameMode {
+	Coop,
+	Deathmatch,
+	// deathmatch 2.0: items respawn
+	Altdeath,
+}
+
+impl FromStr for GameMode {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, ()> {
+		match s {
+			"coop" => Ok(GameMode::Coop),
+			"deathmatch" => Ok(GameMode::Deathmatch),
+			"altdeath" => Ok(GameMode::Altdeath),
+			_ => Err(()),
+		}
 	}
 }
 
-fn get_sessions() -> Result<Vec<Session>, Error> {
-	let kvreq = Request::builder()
-	.method(Method::GET)
-	.uri("http://kv-global.vranish.dev/sessions")
-	.body(Body::from(""))?;
-	let resp = kvreq.send(KV_GLOBAL)?;
-	let body_str = resp.into_body().into_string();
-	match serde_json::from_str(&body_str) {
-		Ok(v) => {
-			let sessions : Vec<Session> = v;
-			Ok(sessions)
-		},
-		_ => {
-			Ok(Vec::<Session>::new())
+/// The game a session is set up to play.
+#[derive(Clone,Serialize,Deserialize)]
+#[serde(default)]
+struct GameSettings {
+	mode: GameMode,
+	map: String,
+	// 1 (I'm too young to die) to 5 (Nightmare!)
+	skill: u8,
+	// hex hash of the WAD everyone needs; empty for the shareware doom1.wad the client ships with
+	wad_hash: String,
+}
+
+impl Default for GameSettings {
+	fn default() -> Self {
+		GameSettings {
+			mode: GameMode::Coop,
+			map: "E1M1".to_string(),
+			skill: 3,
+			wad_hash: String::new(),
 		}
 	}
 }
 
-fn add_pings_to_player(player: &mut Player, json: &str) {
-	match serde_json::from_str(&json) {
-		Ok(p) => {
-			player.pops = p;
-		},
-		_ => {}
+/// What a player looking for a match will accept; None takes anything.
+struct GameFilter {
+	mode: Option<GameMode>,
+	map: Option<String>,
+	skill: Option<u8>,
+	wad_hash: Option<String>,
+}
+
+// Doom's skill levels, "I'm too young to die" to "Nightmare!"
+fn valid_skill(skill: u8) -> bool {
+	(1..=5).contains(&skill)
+}
+
+// ExMy for the episodic IWADs, MAPxx for Doom II and friends; either case
+fn valid_map(map: &str) -> bool {
+	let map = map.as_bytes();
+	match map.len() {
+		4 => map[0].eq_ignore_ascii_case(&b'E') && map[1].is_ascii_digit()
+			&& map[2].eq_ignore_ascii_case(&b'M') && map[3].is_ascii_digit(),
+		5 => map[..3].eq_ignore_ascii_case(b"MAP") && map[3..].iter().all(|c| c.is_ascii_digit()),
+		_ => false,
+	}
+}
+
+impl GameFilter {
+	fn from_headers(req: &Request<Body>) -> Result<Self, LobbyError> {
+		let filter = GameFilter {
+			mode: optional_header(req, "mode")?,
+			map: optional_header(req, "map")?,
+			skill: optional_header(req, "skill")?,
+			wad_hash: optional_header(req, "wad_hash")?,
+		};
+		if !filter.skill.map_or(true, valid_skill) {
+			return Err(LobbyError::BadHeader("skill"));
+		}
+		if !filter.map.as_ref().map_or(true, |map| valid_map(map)) {
+			return Err(LobbyError::BadHeader("map"));
+		}
+		Ok(filter)
+	}
+
+	fn matches(&self, game: &GameSettings) -> bool {
+		self.mode.map_or(true, |mode| mode == game.mode)
+			&& self.map.as_ref().map_or(true, |map| map.eq_ignore_ascii_case(&game.map))
+			&& self.skill.map_or(true, |skill| skill == game.skill)
+			&& self.wad_hash.as_ref().map_or(true, |wad| wad.eq_ignore_ascii_case(&game.wad_hash))
 	}
+
+	// if nothing matched, the new session is set up the way this player asked so the next
+	// player with the same filter finds it
+	fn new_game(&self) -> GameSettings {
+		let default = GameSettings::default();
+		GameSettings {
+			mode: self.mode.unwrap_or(default.mode),
+			map: self.map.as_ref().map(|map| map.to_ascii_uppercase()).unwrap_or(default.map),
+			skill: self.skill.unwrap_or(default.skill),
+			wad_hash: self.wad_hash.as_ref().map(|wad| wad.to_ascii_lowercase()).unwrap_or(default.wad_hash),
+		}
+	}
+}
+
+/// Everything `/join_best_session` is told about what the player is looking for.
+struct MatchRequest {
+	// only used if a new session has to be created
+	max_players: usize,
+	pop: String,
+	pop_strategy: PopStrategy,
+	game: GameFilter,
+	// the player's own ping to each pop they measured; empty if they sent none
+	pings: Vec<Pop>,
+	// sessions the player would see worse latency than this on aren't considered
+	max_latency_ms: u32,
 }
 
-fn write_sessions(sessions: &Vec<Session>) {
-	let json = serde_json::to_string(sessions).unwrap();
+impl MatchRequest {
+	fn from_headers(req: &Request<Body>, config: &Config, catalog: &pops::Catalog) -> Result<Self, LobbyError> {
+		// same JSON as the /add_pings_to_session body
+		let pings = match header_val(req.headers().get("pings")) {
+			"" => Vec::new(),
+			json => parse_pings(json, catalog).map_err(|_| LobbyError::BadHeader("pings"))?,
+		};
+		Ok(MatchRequest {
+			max_players: session_size(config, optional_header(req, "max_players")?),
+			pop: header_val(req.headers().get("pop")).to_string(),
+			pop_strategy: pop_strategy::default_strategy(config),
+			game: GameFilter::from_headers(req)?,
+			pings: pings,
+			max_latency_ms: config.get_parsed("max_join_latency_ms", DEFAULT_MAX_JOIN_LATENCY_MS),
+		})
+	}
 
-	let kvreq = Request::builder()
-	.method(Method::POST)
-	.uri("http://kv-global.vranish.dev/sessions")
-	.body(Body::from(json)).unwrap();
-	let resp = kvreq.send(KV_GLOBAL);
+	// what the player can expect playing at `pop`: their measured ping, or None if they
+	// didn't measure it (or sent no pings at all)
+	fn expected_latency(&self, pop: &str) -> Option<u32> {
+		self.pings.iter().find(|p| p.name == pop).map(|p| p.ping)
+	}
+}
 
+#[derive(Clone,Copy,Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Visibility {
+	Public,
+	Private,
 }
 
-fn get_next_id(sessions: &Vec<Session>) -> u32 {
-	let mut highest = 0;
-	for s in sessions {
-		if s.id > highest {
-			highest = s.id;
+/// The JSON body of `/create_session`. Everything is optional.
+#[derive(Deserialize)]
+#[serde(default)]
+struct CreateSessionBody {
+	max_players: Option<usize>,
+	visibility: Visibility,
+	password: Option<String>,
+	// where the host would like to play; heartbeats can still move the session
+	pop: String,
+	// the pop_strategy setting if not given
+	pop_strategy: Option<PopStrategy>,
+	#[serde(flatten)]
+	game: GameSettings,
+}
+
+impl Default for CreateSessionBody {
+	fn default() -> Self {
+		CreateSessionBody {
+			max_players: None,
+			visibility: Visibility::Public,
+			password: None,
+			pop: String::new(),
+			pop_strategy: None,
+			game: GameSettings::default(),
 		}
 	}
-	highest + 1
 }
 
-fn create_session(playerid: u32, name: &str, pop: &str) -> u32 {
-	let mut sessions = get_sessions().unwrap();
-	let sessionid = get_next_id(&sessions);
-	println!("create_session {}", sessionid);
-	let mut new_session = Session{
-		id: sessionid,
-		pop: pop.to_string(),
-		players: Vec::<Player>::new(),
+// the settings and preferred pop from a `/create_session` body
+fn parse_create_body(json: &str, config: &Config, catalog: &pops::Catalog) -> Result<(SessionSettings,String), LobbyError> {
+	// an empty body takes every default
+	let body: CreateSessionBody = match json.trim() {
+		"" => CreateSessionBody::default(),
+		json => serde_json::from_str(json).map_err(|e| LobbyError::BadBody(e.to_string()))?,
 	};
+	if !valid_skill(body.game.skill) {
+		return Err(LobbyError::BadBody(format!("skill {} is not between 1 and 5", body.game.skill)));
+	}
+	if !valid_map(&body.game.map) {
+		return Err(LobbyError::BadBody(format!("map {} is not ExMy or MAPxx", body.game.map)));
+	}
+	// a session created without a pop gets one from its players' pings
+	if !body.pop.is_empty() && !catalog.is_enabled(&body.pop) {
+		return Err(LobbyError::BadBody(format!("pop {} is not an enabled pop", body.pop)));
+	}
+	let settings = SessionSettings {
+		max_players: session_size(config, body.max_players),
+		private: match body.visibility {
+			Visibility::Public => false,
+			Visibility::Private => true,
+		},
+		password: body.password.filter(|p| !p.is_empty()),
+		game: body.game,
+		pop_strategy: body.pop_strategy.unwrap_or_else(|| pop_strategy::default_strategy(config)),
+	};
+	Ok((settings, body.pop))
+}
+
+impl SessionSettings {
+	/// `/create_private_session` takes its few settings from headers; the rest are defaults.
+	fn private_from_headers(req: &Request<Body>, config: &Config) -> Result<Self, LobbyError> {
+		Ok(SessionSettings {
+			max_players: session_size(config, optional_header(req, "max_players")?),
+			private: true,
+			password: optional_header(req, "password")?,
+			game: GameSettings::default(),
+			pop_strategy: pop_strategy::default_strategy(config),
+		})
+	}
+}
+
+/// Where a session is in its life. New players can only join in `Lobby`.
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SessionState {
+	Lobby,
+	Starting,
+	InGame,
+	Finished,
+}
+
+impl SessionState {
+	fn as_str(&self) -> &'static str {
+		match self {
+			SessionState::Lobby => "lobby",
+			SessionState::Starting => "starting",
+			SessionState::InGame => "in_game",
+			SessionState::Finished => "finished",
+		}
+	}
+
+	// staying put is always allowed so clients can safely retry
+	fn can_become(&self, next: SessionState) -> bool {
+		*self == next || match (*self, next) {
+			(SessionState::Lobby, SessionState::Starting) => true,
+			(SessionState::Lobby, SessionState::Finished) => true,
+			(SessionState::Starting, SessionState::Lobby) => true,
+			(SessionState::Starting, SessionState::InGame) => true,
+			(SessionState::Starting, SessionState::Finished) => true,
+			(SessionState::InGame, SessionState::Finished) => true,
+			_ => false,
+		}
+	}
+}
+
+impl FromStr for SessionState {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, ()> {
+		match s {
+			"lobby" => Ok(SessionState::Lobby),
+			"starting" => Ok(SessionState::Starting),
+			"in_game" => Ok(SessionState::InGame),
+			"finished" => Ok(SessionState::Finished),
+			_ => Err(()),
+		}
+	}
+}
+
+fn header_val(header: Option<&HeaderValue>) -> &str {
+	match header {
+		Some(h) => h.to_str().unwrap_or(""),
+		None => "",
+	}
+}
+
+// only pops in the catalog are accepted, so a made-up or misspelled name can't win, and only
+// pings up to MAX_PING_MS; pings to disabled pops are dropped
+fn parse_pings(json: &str, catalog: &pops::Catalog) -> Result<Vec<Pop>, LobbyError> {
+	let mut pops: Vec<Pop> = serde_json::from_str(json).map_err(|e| LobbyError::BadBody(e.to_string()))?;
+	if let Some(unknown) = pops.iter().find(|p| catalog.get(&p.name).is_none()) {
+		return Err(LobbyError::BadBody(format!("unknown pop {:?}", unknown.name)));
+	}
+	if let Some(slow) = pops.iter().find(|p| p.ping > MAX_PING_MS) {
+		return Err(LobbyError::BadBody(format!("ping {} to {} is over {}ms", slow.ping, slow.name, MAX_PING_MS)));
+	}
+	pops.retain(|p| catalog.is_enabled(&p.name));
+	Ok(pops)
+}
+
+// the requested size clamped to what the `max_players_limit` setting allows
+fn session_size(config: &Config, requested: Option<usize>) -> usize {
+	let limit = config.get_parsed("max_players_limit", DEFAULT_MAX_PLAYERS).max(MIN_PLAYERS);
+	requested.unwrap_or(DEFAULT_MAX_PLAYERS).max(MIN_PLAYERS).min(limit)
+}
+
+// claims a fresh invite code for the session. A clash takes a billion or so open sessions
+// to be likely, so this nearly always settles on the first code.
+fn claim_invite_code(store: &dyn SessionStore, signer: &auth::TokenSigner, session_id: u32, created_at: u64) -> Result<String, Error> {
+	for attempt in 0..MAX_INVITE_ATTEMPTS {
+		let code = invite::generate(signer, session_id, created_at, attempt);
+		if store::claim_invite_code(store, &code, session_id)? {
+			return Ok(code);
+		}
+		println!("create_session {}: invite code {} is taken, trying another", session_id, code);
+	}
+	Err(Error::msg(format!("gave up finding a free invite code for session {}", session_id)))
+}
+
+fn create_session(store: &dyn SessionStore, clock: &dyn Clock, signer: &auth::TokenSigner, playerid: u32, name: &str, pop: &str, settings: &SessionSettings) -> Result<(u32,String), Error> {
+	let now = clock.now_millis();
+	let mut invite_code = String::new();
+	let sessionid = store::insert_session(store, |sessionid| {
+		invite_code = claim_invite_code(store, signer, sessionid, now)?;
+		let mut new_session = Session{
+			id: sessionid,
+			pop: pop.to_string(),
+			players: Vec::<Player>::new(),
+			max_players: settings.max_players,
+			state: SessionState::Lobby,
+			started_at: None,
+			private: settings.private,
+			invite_code: invite_code.clone(),
+			password: settings.password.as_ref().map(|p| auth::PasswordHash::new(signer, p, &format!("{}:{}", sessionid, now))),
+			game: settings.game.clone(),
+			created_at: now,
+			pop_strategy: settings.pop_strategy,
+			pop_challenger: None,
+		};
+		let new_player = Player{
+			id: playerid,
+			name: name.to_string(),
+			index: 0,
+			last_heartbeat: now,
+			pops: Vec::new(),
+			ready: false,
+		};
+		new_session.players.push(new_player);
+		Ok(new_session)
+	})?;
+	println!("create_session {}: added player {} {}{}", sessionid, playerid, name, if settings.private { ", private" } else { "" });
+	Ok((sessionid,invite_code))
+}
+
+fn add_player_to_session(session: &mut Session, id: u32, name: &str, now: u64) -> Result<usize,LobbyError> {
+	if session.state != SessionState::Lobby {
+		return Err(LobbyError::SessionNotJoinable(session.state.as_str()));
+	}
+	let mut slots = vec![false;session.max_players];
+	for p in &session.players {
+		if p.index < slots.len() {
+			slots[p.index] = true;
+		}
+	}
+	let i = slots.iter().position(|taken| !taken).ok_or(LobbyError::SessionFull)?;
 	let new_player = Player{
-		id: playerid,
+		id: id,
 		name: name.to_string(),
-		index: 0,
-		last_heartbeat: Instant::now(),
+		index: i,
+		last_heartbeat: now,
 		pops: Vec::new(),
+		ready: false,
 	};
-	println!("create_session: adding player {} {}", new_player.id, new_player.name);
+	println!("join_session: adding player {} {} to slot {} in session {}", id, name, i, session.id);
+	session.players.push(new_player);
+	Ok(i)
+}
 
-	new_session.players.push(new_player);
-	sessions.push(new_session);
-	write_sessions(&sessions);
-	sessionid
+/// What a new player shows to get into a session.
+struct Credentials<'a> {
+	/// The invite code they came by, if any.
+	code: Option<&'a str>,
+	password: &'a str,
 }
 
-fn join_session_by_index(session_index: usize, id: u32, name: &str) -> Result<(usize,String),&'static str> {
-	let mut sessions = get_sessions().unwrap();
+impl<'a> Credentials<'a> {
+	/// For sessions the player was matched into rather than invited to.
+	const NONE: Credentials<'static> = Credentials {
+		code: None,
+		password: "",
+	};
 
-	let mut slots = [false;4];
-	for p in &sessions[session_index].players {
-		slots[p.index] = true;
-	}
-	for i in 0..4 {
-		if !slots[i] {
-			let new_player = Player{
-				id: id,
-				name: name.to_string(),
-				index: i,
-				last_heartbeat: Instant::now(),
-				pops: Vec::new(),
-			};
-			println!("join_session: adding player {} {} to slot {} in session {}", id, name, i, session_index);
-			sessions[session_index].players.push(new_player);
-			write_sessions(&sessions);
-			return Ok((i,sessions[session_index].pop.clone()));
+	/// A `/join_session` request's `password` header.
+	fn from_headers(req: &'a Request<Body>) -> Self {
+		Credentials {
+			code: None,
+			password: header_val(req.headers().get("password")),
 		}
 	}
-	Err("No player slot found")
 }
 
-fn join_session(session_id: u32, id: u32, name: &str) -> Result<(usize,String),&'static str> {
-	let sessions = get_sessions().unwrap();
+// new players only get into a private session with its invite code; to everyone else it
+// looks like it doesn't exist. Players already in the session don't need the password
+// again.
+fn join_session(store: &dyn SessionStore, clock: &dyn Clock, signer: &auth::TokenSigner, session_id: u32, id: u32, name: &str, credentials: &Credentials) -> Result<(usize,String),LobbyError> {
+	let now = clock.now_millis();
+	let joined = store::try_update_session(store, session_id, |session| {
+		for p in &session.players {
+			if p.id == id {
+				return Ok((p.index,session.pop.clone()));
+			}
+		}
+		// a code only gets you into the session that holds it
+		if credentials.code.map_or(session.private, |code| code != session.invite_code) {
+			return Err(LobbyError::SessionNotFound);
+		}
+		if let Some(hash) = &session.password {
+			if !hash.matches(signer, credentials.password) {
+				return Err(LobbyError::WrongPassword);
+			}
+		}
+		add_player_to_session(session, id, name, now).map(|index| (index,session.pop.clone()))
+	})?;
+	joined.ok_or(LobbyError::SessionNotFound)
+}
 
-	let mut session_index = usize::MAX;
-	for (i,s) in sessions.iter().enumerate() {
-		if s.id == session_id {
-			session_index = i;
-			break;
+// frees the player's slot straight away rather than waiting for them to go stale; returns
+// true if they were the last one out and the session was closed
+fn leave_session(store: &dyn SessionStore, session_id: u32, player_id: u32) -> Result<bool,LobbyError> {
+	let left = store::try_update_session(store, session_id, |session| {
+		if !session.players.iter().any(|p| p.id == player_id) {
+			return Err(LobbyError::NotInSession);
 		}
+		session.players.retain(|p| p.id != player_id);
+		Ok(session.players.is_empty())
+	})?;
+	match left {
+		Some(closed) => {
+			println!("leave_session: player {} left session {}{}", player_id, session_id, if closed { ", closing it" } else { "" });
+			Ok(closed)
+		},
+		None => Err(LobbyError::SessionNotFound),
 	}
-	if session_index == usize::MAX {
-		return Err("Couldn't find session");
-	}
+}
 
-	for p in &sessions[session_index].players {
-		if p.id == id {
-			return Ok((p.index,sessions[session_index].pop.clone()));
+// only players in the session can move it along
+fn set_session_state(store: &dyn SessionStore, session_id: u32, player_id: u32, next: SessionState) -> Result<(),LobbyError> {
+	let updated = store::try_update_session(store, session_id, |session| {
+		if !session.players.iter().any(|p| p.id == player_id) {
+			return Err(LobbyError::NotInSession);
 		}
+		if !session.state.can_become(next) {
+			return Err(LobbyError::InvalidTransition(session.state.as_str(), next.as_str()));
+		}
+		println!("session {}: {} -> {}", session_id, session.state.as_str(), next.as_str());
+		if next == SessionState::Lobby {
+			// a start that fell through means everyone readies up again
+			for p in &mut session.players {
+				p.ready = false;
+			}
+			session.started_at = None;
+		}
+		session.state = next;
+		Ok(())
+	})?;
+	updated.ok_or(LobbyError::SessionNotFound)
+}
+
+// how many ready players it takes to start; 0 (the default) means every player, and then
+// there have to be at least MIN_PLAYERS of them
+fn min_ready_players(config: &Config) -> usize {
+	config.get_parsed("min_ready_players", 0)
+}
+
+// moves a lobby to starting once enough players are ready
+fn start_if_ready(session: &mut Session, min_ready: usize, now: u64) {
+	if session.state != SessionState::Lobby {
+		return;
+	}
+	let ready = session.players.iter().filter(|p| p.ready).count();
+	let enough = match min_ready {
+		0 => ready == session.players.len() && ready >= MIN_PLAYERS,
+		n => ready >= n,
+	};
+	if enough {
+		println!("session {}: {} of {} players ready, starting", session.id, ready, session.players.len());
+		session.state = SessionState::Starting;
+		session.started_at = Some(now);
 	}
+}
+
+// returns the session's state afterwards, so the caller can tell if this started the match
+fn set_ready(store: &dyn SessionStore, clock: &dyn Clock, config: &Config, session_id: u32, player_id: u32, ready: bool) -> Result<SessionState,LobbyError> {
+	let now = clock.now_millis();
+	let min_ready = min_ready_players(config);
+	let updated = store::try_update_session(store, session_id, |session| {
+		match session.players.iter_mut().find(|p| p.id == player_id) {
+			Some(p) => p.ready = ready,
+			None => return Err(LobbyError::NotInSession),
+		}
+		start_if_ready(session, min_ready, now);
+		Ok(session.state)
+	})?;
+	updated.ok_or(LobbyError::SessionNotFound)
+}
 
-	join_session_by_index(session_index as usize, id, name)
+#[derive(Serialize)]
+struct PlayerPing {
+	player_id: u32,
+	// None if the player never measured the chosen pop
+	ping: Option<u32>,
+}
+
+/// Where a session should be played and what that means for each player.
+struct Placement {
+	pop: String,
+	pings: Vec<PlayerPing>,
 }
 
-fn get_best_pop_and_update(sessions: &Vec<Session>, sessionid: u32) -> Result<String,&'static str> {
-	let mut pop_vecs : Vec<Vec<(&String,&u32)>> = Vec::new();
+/// When a heartbeat may move a session to a better pop.
+struct PopSwitch<'a> {
+	// a challenger has to score at least this much better than the current pop...
+	margin: f32,
+	// ...on every heartbeat for this many milliseconds
+	hold_ms: u64,
+	// what a pop a player never measured counts as for them; None leaves out every pop that
+	// some player hasn't measured
+	missing_ping: Option<u32>,
+	// pops switched off in the catalog since players measured them don't count
+	catalog: &'a pops::Catalog,
+}
+
+impl<'a> PopSwitch<'a> {
+	fn from_config(config: &Config, catalog: &'a pops::Catalog) -> Self {
+		PopSwitch {
+			margin: config.get_parsed("pop_switch_margin_ms", DEFAULT_POP_SWITCH_MARGIN_MS),
+			hold_ms: config.get_parsed("pop_switch_hold_ms", DEFAULT_POP_SWITCH_HOLD_MS),
+			missing_ping: match config.get_parsed("missing_ping_penalty_ms", 0) {
+				0 => None,
+				ms => Some(ms),
+			},
+			catalog: catalog,
+		}
+	}
+}
+
+// scores every pop the players measured and moves the session to the best one once it has
+// beaten the current pop by `switch.margin` on every heartbeat for `switch.hold_ms`. A
+// session with no pop yet, or whose pop is no longer a candidate, moves straight away. The
+// hold is timed rather than counted, so it lasts as long however many players are
+// heartbeating.
+fn get_best_pop_and_update(session: &mut Session, switch: &PopSwitch, now: u64) -> Result<Placement,&'static str> {
 	let mut merged_pops: HashMap<String, Vec<u32>> = HashMap::new();
 
-	for session in sessions {
-		if session.id == sessionid {
-			for player in &session.players {
-				for pop in &player.pops {
-					merged_pops.entry(pop.name.to_string()).or_insert(Vec::new()).push(pop.ping);
-				}
+	// players who haven't sent pings yet don't get a say
+	let active = session.players.iter().filter(|p| !p.pops.is_empty()).collect::<Vec<&Player>>();
+	for player in &active {
+		for pop in &player.pops {
+			merged_pops.entry(pop.name.to_string()).or_insert_with(Vec::new);
+		}
+	}
+	// every pop gets exactly one ping per active player, or is dropped
+	for (name, pings) in merged_pops.iter_mut() {
+		for player in &active {
+			match (player.pops.iter().find(|p| p.name == *name), switch.missing_ping) {
+				(Some(pop), _) => pings.push(pop.ping),
+				(None, Some(penalty)) => pings.push(penalty),
+				(None, None) => {
+					pings.clear();
+					break;
+				},
 			}
+		}
+	}
+	merged_pops.retain(|name, pings| !pings.is_empty() && switch.catalog.is_enabled(name));
 
-			if (merged_pops.is_empty()) {
-				return Err("no pops");
+	if merged_pops.is_empty() {
+		return Err("no pop measured by every player");
+	}
+	let merged_as_vec: Vec<(&String, &Vec<u32>)> = merged_pops.iter().collect();
+
+	let mut sorted_pops = merged_as_vec.iter().map(|(n,ps)| (n,session.pop_strategy.score(ps))).collect::<Vec<(&&String,f32)>>();
+	sorted_pops.sort_by(|a,b| (a.1.partial_cmp(&b.1).unwrap_or(Equal)));
+	let (best, best_score) = (sorted_pops[0].0.to_string(), sorted_pops[0].1);
+
+	match sorted_pops.iter().find(|(n,_)| n.as_str() == session.pop).map(|(_,score)| *score) {
+		Some(_) if best == session.pop => session.pop_challenger = None,
+		Some(current_score) if current_score - best_score >= switch.margin => {
+			let since = match &session.pop_challenger {
+				Some(challenger) if challenger.pop == best => challenger.since,
+				_ => now,
+			};
+			if now.saturating_sub(since) >= switch.hold_ms {
+				println!("session {}: moving from {} to {} after {}ms", session.id, session.pop, best, now.saturating_sub(since));
+				session.pop = best;
+				session.pop_challenger = None;
+			} else {
+				session.pop_challenger = Some(PopChallenger {
+					pop: best,
+					since: since,
+				});
 			}
-			let merged_as_vec: Vec<(&String, &Vec<u32>)> = merged_pops.iter().collect();
+		},
+		// not enough better, so the challenger has to start over
+		Some(_) => session.pop_challenger = None,
+		None => {
+			println!("session {}: {:?} is not a candidate, moving to {}", session.id, session.pop, best);
+			session.pop = best;
+			session.pop_challenger = None;
+		},
+	}
+
+	let pop = session.pop.clone();
+	let pings = session.players.iter().map(|player| PlayerPing {
+		player_id: player.id,
+		ping: player.pops.iter().find(|p| p.name == pop).map(|p| p.ping),
+	}).collect();
+	Ok(Placement {
+		pop: pop,
+		pings: pings,
+	})
+}
+
+// a heartbeat stamped ahead of `now` by another host's clock counts as fresh
+fn is_stale(player: &Player, now: u64) -> bool {
+	now.saturating_sub(player.last_heartbeat) >= HEARTBEAT_TIMEOUT_MS
+}
+
+fn prune_stale_players(session: &mut Session, now: u64) {
+	session.players.retain(|p| !is_stale(p, now));
+}
 
-			//.sort_by(|a,b| (b.1.partial_cmp(&a.1).unwrap_or(Equal)))
-			let mut sorted_pops = merged_as_vec.iter().map(|(n,ps)| (n,ps.iter().sum::<u32>() as f32 / ps.len() as f32)).collect::<Vec<(&&String,f32)>>();
-			sorted_pops.sort_by(|a,b| (a.1.partial_cmp(&b.1).unwrap_or(Equal)));
-			return Ok(sorted_pops[0].0.to_string());
+// only sessions that actually have stale players are written back
+fn prune_stale_sessions(store: &dyn SessionStore, clock: &dyn Clock, sessions: &mut Vec<Session>) -> Result<(), Error> {
+	let now = clock.now_millis();
+	for session in sessions.iter_mut() {
+		if session.players.iter().any(|p| is_stale(p, now)) {
+			store::update_session(store, session.id, |s| prune_stale_players(s, now))?;
+			prune_stale_players(session, now);
 		}
 	}
-//	return Ok(&session.pop);
+	sessions.retain(|s| {
+		!s.players.is_empty()
+	});
+	Ok(())
+}
+
+// every live session, private ones included
+// returns the player's slot
+fn update_name(store: &dyn SessionStore, session_id: u32, player_id: u32, name: &str) -> Result<usize,LobbyError> {
+	let updated = store::try_update_session(store, session_id, |session| {
+		match session.players.iter_mut().find(|p| p.id == player_id) {
+			Some(p) => {
+				p.name = name.to_string();
+				Ok(p.index)
+			},
+			None => Err(LobbyError::NotInSession),
+		}
+	})?;
+	updated.ok_or(LobbyError::SessionNotFound)
+}
+
+// only a player in the session can move it, and only to a pop the catalog has enabled
+fn update_pop(store: &dyn SessionStore, catalog: &pops::Catalog, session_id: u32, player_id: u32, pop: &str) -> Result<(),LobbyError> {
+	if !catalog.is_enabled(pop) {
+		return Err(LobbyError::BadHeader("pop"));
+	}
+	let updated = store::try_update_session(store, session_id, |session| {
+		if !session.players.iter().any(|p| p.id == player_id) {
+			return Err(LobbyError::NotInSession);
+		}
+		session.pop = pop.to_string();
+		session.pop_challenger = None;
+		Ok(())
+	})?;
+	updated.ok_or(LobbyError::SessionNotFound)
+}
+
+// replaces whatever pings the player reported before
+fn add_pings(store: &dyn SessionStore, session_id: u32, player_id: u32, pops: &[Pop]) -> Result<(),LobbyError> {
+	let updated = store::try_update_session(store, session_id, |session| {
+		match session.players.iter_mut().find(|p| p.id == player_id) {
+			Some(p) => p.pops = pops.to_vec(),
+			None => return Err(LobbyError::NotInSession),
+		}
+		Ok(())
+	})?;
+	updated.ok_or(LobbyError::SessionNotFound)
+}
+
+fn load_sessions(store: &dyn SessionStore, clock: &dyn Clock) -> Result<Vec<Session>, Error> {
+	let mut sessions = store::get_sessions(store)?;
+	prune_stale_sessions(store, clock, &mut sessions)?;
+	Ok(sessions)
+}
+
+// the public sessions; `state` limits the list to sessions in that state
+fn list_sessions(store: &dyn SessionStore, clock: &dyn Clock, state: Option<SessionState>) -> Result<Vec<Session>, Error> {
+	let mut sessions = load_sessions(store, clock)?;
+	sessions.retain(|s| !s.private && state.map_or(true, |state| s.state == state));
+	Ok(sessions)
+}
+
+fn join_by_code(store: &dyn SessionStore, clock: &dyn Clock, signer: &auth::TokenSigner, code: &str, id: u32, name: &str, password: &str) -> Result<(u32,usize,String),LobbyError> {
+	let code = invite::normalize(code);
+	// sessions from before invite codes have an empty one
+	if code.is_empty() {
+		return Err(LobbyError::SessionNotFound);
+	}
+	match store::find_invite_code(store, &code)? {
+		Some(session_id) => {
+			println!("/join_by_code {} joining session {}", id, session_id);
+			let credentials = Credentials {
+				code: Some(&code),
+				password: password,
+			};
+			join_session(store,clock,signer,session_id,id,name,&credentials).map(|(index,pop)| (session_id,index,pop))
+		},
+		None => Err(LobbyError::SessionNotFound),
+	}
+}
 
-	Err("Could not find session")
+/// What a heartbeat tells the client about its session.
+struct HeartbeatStatus {
+	/// The best pop, or an error if nobody has reported pings yet.
+	pop: Result<Placement,&'static str>,
+	state: SessionState,
+	started_at: Option<u64>,
 }
 
-fn prune_stale_sessions(sessions: &mut Vec<Session>, playerid: u32, sessionid: u32, do_update: bool) {
-	let now = Instant::now();
-	for session in &mut sessions.iter_mut() {
-		for p in &mut session.players {
-			if do_update && p.id == playerid && sessionid == session.id {
-				p.last_heartbeat = now;
+// refreshes the player's heartbeat and reports on the session, or None if the session is
+// gone. Dropping a stale player who wasn't ready can leave everyone else ready, so this
+// can start the match too.
+fn heartbeat(store: &dyn SessionStore, clock: &dyn Clock, config: &Config, catalog: &pops::Catalog, session_id: u32, player_id: u32) -> Result<Option<HeartbeatStatus>, LobbyError> {
+	let now = clock.now_millis();
+	let min_ready = min_ready_players(config);
+	let switch = PopSwitch::from_config(config, catalog);
+	store::try_update_session(store, session_id, |session| {
+		match session.players.iter_mut().find(|p| p.id == player_id) {
+			Some(p) => p.last_heartbeat = now,
+			None => return Err(LobbyError::NotInSession),
+		}
+		prune_stale_players(session, now);
+		start_if_ready(session, min_ready, now);
+		Ok(HeartbeatStatus {
+			pop: get_best_pop_and_update(session, &switch, now),
+			state: session.state,
+			started_at: session.started_at,
+		})
+	})
+}
+
+// `ranker` orders the sessions worth trying; if none of them takes the player, they get a
+// new session set up from `wants`
+fn join_best_session(store: &dyn SessionStore, clock: &dyn Clock, signer: &auth::TokenSigner, ranker: &ranker::SessionRanker, id: u32, name: &str, wants: &MatchRequest) -> Result<(u32,usize,String),LobbyError> {
+	let sessions = load_sessions(store, clock)?;
+	println!("After prune, we have {} sessions", sessions.len());
+	// if we are already in a session, return that one. A finished one is left behind instead,
+	// or a player looking for their next match would keep being sent back to the last.
+	for s in &sessions {
+		if let Some(p) = s.players.iter().find(|p| p.id == id) {
+			if s.state == SessionState::Finished {
+				println!("/join_best_session {} leaving finished session {}", id, s.id);
+				store::update_session(store, s.id, |session| session.players.retain(|p| p.id != id))?;
+				continue;
 			}
+			println!("/join_best_session {} rejoining existing session {}", id,s.id);
+			return Ok((s.id,p.index,s.pop.clone()));
 		}
-		session.players.retain(|p| {
-			now.duration_since(p.last_heartbeat) < Duration::from_secs(60 * 1)
-		});
 	}
-	sessions.retain(|s| {
-		s.players.len() > 0
-	});
-	write_sessions(&sessions);
+	let ranked = ranker.rank(&sessions, wants, clock.now_millis());
+	// our copy of a session can be out of date, so if it filled up or started in the
+	// meantime move on to the next one
+	for s in ranked.iter().take(MAX_JOIN_ATTEMPTS) {
+		println!("/join_best_session {} joining existing session {}", id,s.id);
+		match join_session(store,clock,signer,s.id,id,name,&Credentials::NONE) {
+			Ok((index,pop)) => return Ok((s.id,index,pop)),
+			Err(LobbyError::SessionFull) | Err(LobbyError::SessionNotJoinable(_)) | Err(LobbyError::SessionNotFound) => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	let settings = SessionSettings {
+		max_players: wants.max_players,
+		private: false,
+		password: None,
+		game: wants.game.new_game(),
+		pop_strategy: wants.pop_strategy,
+	};
+	let (sessionid,_) = create_session(store,clock,signer,id,name,&wants.pop,&settings)?;
+	println!("/join_best_session {} create new session {}", id,sessionid);
+	Ok((sessionid,0,wants.pop.clone()))
+}
+
+/// A session as legacy `/sessions` lists it: only what the shipped client knows about.
+/// Anything added since stays on `/v2/sessions`.
+#[derive(Serialize)]
+struct LegacySession<'a> {
+	id: u32,
+	pop: &'a str,
+	players: Vec<LegacyPlayer<'a>>,
+}
+
+#[derive(Serialize)]
+struct LegacyPlayer<'a> {
+	name: &'a str,
+	id: u32,
+	index: usize,
+	last_heartbeat: u64,
+	pops: &'a [Pop],
+}
+
+impl<'a> From<&'a Session> for LegacySession<'a> {
+	fn from(s: &'a Session) -> Self {
+		LegacySession {
+			id: s.id,
+			pop: &s.pop,
+			players: s.players.iter().map(|p| LegacyPlayer {
+				name: &p.name,
+				id: p.id,
+				index: p.index,
+				last_heartbeat: p.last_heartbeat,
+				pops: &p.pops,
+			}).collect(),
+		}
+	}
+}
+
+/// A pop as legacy `/get_pops` lists it, the way the shipped client reads it.
+#[derive(Serialize)]
+struct LegacyPop<'a> {
+	name: &'a str,
+	ip: &'a str,
+}
+
+impl<'a> From<&'a pops::CatalogPop> for LegacyPop<'a> {
+	fn from(p: &'a pops::CatalogPop) -> Self {
+		LegacyPop {
+			name: &p.name,
+			ip: &p.ip,
+		}
+	}
+}
+
+fn cors_response<B: Into<Body>>(status: StatusCode, body: B) -> Result<Response<Body>, LobbyError> {
+	Ok(Response::builder()
+	.status(status)
+	.header("Access-Control-Allow-Origin","*")
+	.header("Access-Control-Allow-Headers","*")
+	.header("Vary","Origin")
+	.body(body.into())?)
+}
+
+// the shipped client looks for these exact failure bodies, so legacy routes keep them and
+// only the status code says what went wrong. Headers that didn't parse always got an empty
+// body.
+fn legacy_failure(route: &str, e: LobbyError, body: &str) -> Result<Response<Body>, LobbyError> {
+	println!("{} failed: {}", route, e);
+	match e {
+		LobbyError::BadHeader(_) => cors_response(e.status(), ""),
+		_ => cors_response(e.status(), body),
+	}
+}
+
+// runs a legacy route, answering any failure with `body` through `legacy_failure`
+fn legacy_route<F>(route: &str, body: &str, handle: F) -> Result<Response<Body>, LobbyError>
+where F: FnOnce() -> Result<Response<Body>, LobbyError> {
+	match handle() {
+		Ok(resp) => Ok(resp),
+		Err(e) => legacy_failure(route, e, body),
+	}
+}
+
+fn header_u32(req: &Request<Body>, name: &'static str) -> Result<u32, LobbyError> {
+	match header_val(req.headers().get(name)).parse::<u32>() {
+		Ok(v) => Ok(v),
+		_ => {
+			println!("Couldn't get {} from {} header: {}", name, req.uri().path(), header_val(req.headers().get(name)));
+			Err(LobbyError::BadHeader(name))
+		}
+	}
+}
+
+// None when the header is absent, an error when it's there but doesn't parse
+fn optional_header<T: FromStr>(req: &Request<Body>, name: &'static str) -> Result<Option<T>, LobbyError> {
+	let val = header_val(req.headers().get(name));
+	if val.is_empty() {
+		return Ok(None);
+	}
+	match val.parse::<T>() {
+		Ok(v) => Ok(Some(v)),
+		_ => Err(LobbyError::BadHeader(name)),
+	}
 }
 
-// let's keep this simple for now
-fn rank_session(s: &Session) -> i32 {
-	match s.players.len() == 4 {
-		true => i32::MIN,
-		false => s.players.len() as i32
+fn check_token<'a>(req: &Request<Body>, signer: &'a Result<auth::TokenSigner, Error>, player_id: u32) -> Result<&'a auth::TokenSigner, LobbyError> {
+	auth::authorize(signer, player_id, header_val(req.headers().get("token")))
+}
+
+/// The player a session route acts for, from the `playerid` and `sessionid` headers, once
+/// their token has checked out.
+struct SessionCaller<'a> {
+	player_id: u32,
+	session_id: u32,
+	signer: &'a auth::TokenSigner,
+}
+
+impl<'a> SessionCaller<'a> {
+	fn from_headers(req: &Request<Body>, signer: &'a Result<auth::TokenSigner, Error>) -> Result<Self, LobbyError> {
+		let player_id = header_u32(req, "playerid")?;
+		let session_id = header_u32(req, "sessionid")?;
+		Ok(SessionCaller {
+			player_id: player_id,
+			session_id: session_id,
+			signer: check_token(req, signer, player_id)?,
+		})
 	}
-	// later perhaps look at update time
+}
+
+// for clients timing round trips to each pop's ip. Fastly names the pop that served the
+// request in FASTLY_POP; a client can send the pop it meant to reach as `target` so we can
+// see when traffic lands somewhere else.
+fn ping(req: &Request<Body>, clock: &dyn Clock) -> Result<Response<Body>, LobbyError> {
+	let pop = env::var("FASTLY_POP").unwrap_or_default();
+	let target = header_val(req.headers().get("target"));
+	if !target.is_empty() && !target.eq_ignore_ascii_case(&pop) {
+		println!("/ping meant for {} was answered by {}", target, pop);
+	}
+	let reply = PingReply {
+		pop: pop,
+		server_time: clock.now_millis(),
+	};
+	let mut resp = cors_response(StatusCode::OK, serde_json::to_string(&reply).unwrap())?;
+	resp.headers_mut().insert("Content-Type", HeaderValue::from_static("application/json"));
+	// every ping has to make the round trip
+	resp.headers_mut().insert("Cache-Control", HeaderValue::from_static("no-store"));
+	Ok(resp)
 }
 
 /// If `main` returns an error, a 500 error response will be delivered to the client.
@@ -290,6 +1066,30 @@ fn rank_session(s: &Session) -> i32 {
             .body(Body::from("This method is not allowed"))?);
     }
 
+	let clock = clock::SystemClock;
+	// a ping is timing the round trip, so it's answered before anything else is loaded
+	if req.method() == Method::GET && req.uri().path() == "/ping" {
+		return match ping(&req, &clock) {
+			Ok(resp) => Ok(resp),
+			Err(e) => e.to_response(),
+		};
+	}
+	let store = store::open(&clock);
+	let config = Config::open();
+	let signer = auth::TokenSigner::from_config(&config);
+	let catalog = pops::Catalog::load(&config);
+
+	let path = req.uri().path().to_string();
+	match handle_request(req, &*store, &clock, &config, &signer, &catalog) {
+		Ok(resp) => Ok(resp),
+		Err(e) => {
+			println!("{} failed: {}", path, e);
+			e.to_response()
+		}
+	}
+}
+
+fn handle_request(req: Request<Body>, store: &dyn SessionStore, clock: &dyn Clock, config: &Config, signer: &Result<auth::TokenSigner, Error>, catalog: &pops::Catalog) -> Result<Response<Body>, LobbyError> {
     // Pattern match on the request method and path.
     match (req.method(), req.uri().path()) {
 
@@ -298,389 +1098,173 @@ fn rank_session(s: &Session) -> i32 {
             .status(StatusCode::OK)
             .body(Body::from("Welcome to the Doom@Edge Session Services"))?),
 
+		// hand out a new player id and the token that proves it, as "<playerid>,<token>"
+		(&Method::POST, "/register") => {
+			let signer = signer.as_ref().map_err(|_| LobbyError::AuthUnavailable)?;
+			let player_id = store::allocate_player_id(store)?;
+			println!("/register issued player {}", player_id);
+			cors_response(StatusCode::OK, format!("{},{}",player_id,signer.sign(player_id)))
+		},
 		// get sessions from our kv
 		// return them to the client in this form:
 		// <number of entries>,[<sessionid>,<num_players>,[playerid,playername]...<playerN>],
-		(&Method::GET, "/sessions") => {
-			let s = get_sessions();
-			match s {
-				Ok(mut sessions) => {
-					prune_stale_sessions(&mut sessions,0,0,false);
-
-					Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(serde_json::to_string(&sessions).unwrap()))?)
-				},
-				_ => {
-					Ok(Response::builder()
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.status(StatusCode::OK)
-					.body(Body::from("0"))?)
-				}
-			}
-		},
-		(&Method::GET, "/join_best_session") => {
-			let id = match header_val(req.headers().get("id")).parse::<u32>() {
-				Ok(id) => {id},
-				_ => {
-					println!("Couldn't get id from /join_best_session header: {}", header_val(req.headers().get("id")));
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?);
-				}
-			};
+		(&Method::GET, "/sessions") => legacy_route("/sessions", "0", || {
+			let sessions = list_sessions(store, clock, optional_header(&req, "state")?)?;
+			let legacy = sessions.iter().map(LegacySession::from).collect::<Vec<LegacySession>>();
+			cors_response(StatusCode::OK, serde_json::to_string(&legacy).unwrap())
+		}),
+		(&Method::GET, "/join_best_session") => legacy_route("/join_best_session", "-1,-1,0", || {
+			let id = header_u32(&req, "id")?;
+			let signer = check_token(&req, signer, id)?;
+			let name = header_val(req.headers().get("name"));
+			let wants = MatchRequest::from_headers(&req, config, catalog)?;
+			let (sessionid,index,pop) = join_best_session(store,clock,signer,&ranker::SessionRanker::from_config(config),id,name,&wants)?;
+			cors_response(StatusCode::OK, format!("{},{},{}",sessionid,index,pop))
+		}),
+		// a new private session for the caller, as "<sessionid>,<index>,<invite code>"
+		(&Method::POST, "/create_private_session") => {
+			let id = header_u32(&req, "id")?;
+			let signer = check_token(&req, signer, id)?;
 			let name = header_val(req.headers().get("name"));
 			let pop = header_val(req.headers().get("pop"));
-			let s = get_sessions();
-			match s {
-				Ok(mut sessions) => {
-					prune_stale_sessions(&mut sessions,0,0,false);
-					println!("After prune, we have {} sessions", sessions.len());
-					let mut best = i32::MIN;
-					let mut best_index : i32 = -1;
-					// if we are already in a session, return that one
-					for (i,s) in sessions.iter().enumerate() {
-						for p in &s.players {
-							if p.id == id {
-								println!("/join_best_session {} rejoining existing session {}", id,s.id);
-								return Ok(Response::builder()
-								.status(StatusCode::OK)
-								.header("Access-Control-Allow-Origin","*")
-								.header("Access-Control-Allow-Headers","*")
-								.header("Vary","Origin")
-								.body(Body::from(format!("{},{},{}",s.id,p.index,s.pop)))?);
-							}
-							let rank = rank_session(s);
-							if rank > best {
-								best = rank;
-								best_index = i as i32;
-							}
-						}
-					}
-					if best_index > -1 {
-						let sessionid = sessions[best_index as usize].id;
-						println!("/join_best_session {} joining existing session {}", id,sessionid);
-						match join_session_by_index(best_index as usize,id,name) {
-							Ok((index,pop)) => {
-								return Ok(Response::builder()
-								.status(StatusCode::OK)
-								.header("Access-Control-Allow-Origin","*")
-								.header("Access-Control-Allow-Headers","*")
-								.header("Vary","Origin")
-								.body(Body::from(format!("{},{},{}",sessionid,index,pop)))?);
-							},
-							_ => {
-								return Ok(Response::builder()
-								.status(StatusCode::OK)
-								.header("Access-Control-Allow-Origin","*")
-								.header("Access-Control-Allow-Headers","*")
-								.header("Vary","Origin")
-								.body(Body::from(format!("-1,-1,0")))?);
-							}
-						}
-					} else {
-						let sessionid = create_session(id,name,pop);
-						println!("/join_best_session {} create new session {}", id,sessionid);
-						return Ok(Response::builder()
-						.header("Access-Control-Allow-Origin","*")
-						.header("Access-Control-Allow-Headers","*")
-						.header("Vary","Origin")
-						.status(StatusCode::OK)
-						.body(Body::from(format!("{},{},{}",sessionid,0,pop)))?);
-					}
-				},
-				_ => {
-					let sessionid = create_session(id,name,pop);
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(format!("{},{},{}",sessionid,0,pop)))?);
-				}
-			}
+			let settings = SessionSettings::private_from_headers(&req, config)?;
+			let (sessionid,code) = create_session(store,clock,signer,id,name,pop,&settings)?;
+			cors_response(StatusCode::OK, format!("{},0,{}",sessionid,code))
 		},
-		(&Method::GET, "/join_session") => {
-			let name = header_val(req.headers().get("name"));
-			let player_id = match header_val(req.headers().get("playerid")).parse::<u32>() {
-				Ok(id) => id,
-				_ => {
-					println!("Couldn't get player id from join_session: {}", header_val(req.headers().get("playerid")));
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?);
-				}
-			};
-			let session_id = match header_val(req.headers().get("sessionid")).parse::<u32>() {
-				Ok(id) => id,
-				_ => {
-					println!("Couldn't get session id from join_session: {}", header_val(req.headers().get("sessionid")));
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?);
-				}
-			};
-			match join_session(session_id,player_id,name) {
-				Ok((index,pop)) => {
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(format!("{},{}",index,pop)))?);
-				}
-				_ => {
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from("-1,\"\""))?);
-				}
-			}
+		// a new session set up from the JSON body, as "<sessionid>,<index>,<invite code>"
+		(&Method::POST, "/create_session") => {
+			let id = header_u32(&req, "id")?;
+			let signer = check_token(&req, signer, id)?;
+			let name = header_val(req.headers().get("name")).to_string();
+			let (settings, pop) = parse_create_body(&req.into_body().into_string(), config, catalog)?;
+			let (sessionid,code) = create_session(store,clock,signer,id,&name,&pop,&settings)?;
+			cors_response(StatusCode::OK, format!("{},0,{}",sessionid,code))
 		},
-		(&Method::POST, "/update_name_in_session") => {
-			let player_id = match header_val(req.headers().get("playerid")).parse::<u32>() {
-				Ok(id) => id,
-				_ => {
-					println!("Couldn't get player id from update_name_in_session: {}", header_val(req.headers().get("playerid")));
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?);
-				}
-			};
-			let session_id = match header_val(req.headers().get("sessionid")).parse::<u32>() {
-				Ok(id) => id,
-				_ => {
-					println!("Couldn't get session id from update_name_in_session: {}", header_val(req.headers().get("sessionid")));
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?);
-				}
-			};
+		(&Method::POST, "/join_by_code") => legacy_route("/join_by_code", "-1,-1,0", || {
+			let player_id = header_u32(&req, "playerid")?;
+			let signer = check_token(&req, signer, player_id)?;
 			let name = header_val(req.headers().get("name"));
-			let s = get_sessions();
-			match s {
-				Ok(mut sessions) => {
-					for session in &mut sessions {
-						if session.id == session_id {
-							for p in &mut session.players {
-								if p.id == player_id {
-									p.name = name.to_string();
-								}
-							}
-						}
-					}
-					write_sessions(&sessions);
-
-					Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?)
-				},
-				_ => {
-					Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?)
-				}
-			}
+			let code = header_val(req.headers().get("code"));
+			let password = header_val(req.headers().get("password"));
+			let (sessionid,index,pop) = join_by_code(store,clock,signer,code,player_id,name,password)?;
+			cors_response(StatusCode::OK, format!("{},{},{}",sessionid,index,pop))
+		}),
+		(&Method::GET, "/join_session") => legacy_route("/join_session", "-1,\"\"", || {
+			let name = header_val(req.headers().get("name"));
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			let credentials = Credentials::from_headers(&req);
+			let (index,pop) = join_session(store,clock,caller.signer,caller.session_id,caller.player_id,name,&credentials)?;
+			cors_response(StatusCode::OK, format!("{},{}",index,pop))
+		}),
+		(&Method::POST, "/leave_session") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			leave_session(store, caller.session_id, caller.player_id)?;
+			cors_response(StatusCode::OK, "")
 		},
-		(&Method::POST, "/update_pop_in_session") => {
-			let session_id = match header_val(req.headers().get("sessionid")).parse::<u32>() {
-				Ok(id) => id,
-				_ => {
-					println!("Couldn't get session id from update_pop_in_session: {}", header_val(req.headers().get("sessionid")));
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?);
-				}
-			};
-			let pop = header_val(req.headers().get("pop"));
-			let s = get_sessions();
-			match s {
-				Ok(mut sessions) => {
-					for session in &mut sessions {
-						if session.id == session_id {
-							session.pop = pop.to_string();
-						}
-					}
-					write_sessions(&sessions);
-
-					Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?)
-				},
-				_ => {
-					Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?)
-				}
-			}
+		(&Method::POST, "/start_session") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			set_session_state(store, caller.session_id, caller.player_id, SessionState::Starting)?;
+			cors_response(StatusCode::OK, "")
 		},
-		(&Method::POST, "/heartbeat") => {
-			let player_id = match header_val(req.headers().get("playerid")).parse::<u32>() {
-				Ok(id) => id,
-				_ => {
-					println!("Couldn't get player id from heartbeat: {}", header_val(req.headers().get("playerid")));
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?);
-				}
-			};
-			let session_id = match header_val(req.headers().get("sessionid")).parse::<u32>() {
-				Ok(id) => id,
-				_ => {
-					println!("Couldn't get session id from heartbeat: {}", header_val(req.headers().get("sessionid")));
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?);
-				}
-			};
-			let s = get_sessions();
-			match s {
-				Ok(mut sessions) => {
-					prune_stale_sessions(&mut sessions,player_id,session_id,true);
-
-					match get_best_pop_and_update(&sessions, session_id) {
-						Ok(new_pop) => {
-							write_sessions(&sessions);
-							println!("heartbeat for {} {}, returning {}", session_id, player_id, new_pop);
-
-							Ok(Response::builder()
-							.status(StatusCode::OK)
-							.header("Access-Control-Allow-Origin","*")
-							.header("Access-Control-Allow-Headers","*")
-							.header("Vary","Origin")
-							.body(Body::from(format!("{}",new_pop)))?)
-						},
-						_ => {
-							Ok(Response::builder()
-							.status(StatusCode::OK)
-							.header("Access-Control-Allow-Origin","*")
-							.header("Access-Control-Allow-Headers","*")
-							.header("Vary","Origin")
-							.body(Body::from(format!("")))?)
-						}
-					}
-				},
-				_ => {
-					Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?)
-				}
-			}
+		(&Method::POST, "/finish_session") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			set_session_state(store, caller.session_id, caller.player_id, SessionState::Finished)?;
+			cors_response(StatusCode::OK, "")
 		},
-		(&Method::GET, "/get_pops") => {
-			Ok(Response::builder()
-			.status(StatusCode::OK)
-			.header("Access-Control-Allow-Origin","*")
-			.header("Access-Control-Allow-Headers","*")
-			.header("Vary","Origin")
-			.body(Body::from(serde_json::to_string(&POPS).unwrap()))?)
+		// any other transition, e.g. state: in_game once the game is actually running
+		(&Method::POST, "/update_state_in_session") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			let state = optional_header(&req, "state")?.ok_or(LobbyError::BadHeader("state"))?;
+			set_session_state(store, caller.session_id, caller.player_id, state)?;
+			cors_response(StatusCode::OK, "")
 		},
-		(&Method::POST, "/add_pings_to_session") => {
-			let player_id = match header_val(req.headers().get("playerid")).parse::<u32>() {
-				Ok(id) => id,
-				_ => {
-					println!("Couldn't get player id from add_ping_to_session: {}", header_val(req.headers().get("playerid")));
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?);
-				}
-			};
-			let session_id = match header_val(req.headers().get("sessionid")).parse::<u32>() {
-				Ok(id) => id,
-				_ => {
-					println!("Couldn't get session id from add_ping_to_session: {}", header_val(req.headers().get("sessionid")));
-					return Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?);
-				}
+		// `ready: false` takes it back; defaults to true
+		(&Method::POST, "/ready") => {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			let ready = optional_header(&req, "ready")?.unwrap_or(true);
+			let state = set_ready(store, clock, config, caller.session_id, caller.player_id, ready)?;
+			cors_response(StatusCode::OK, state.as_str())
+		},
+		(&Method::POST, "/update_name_in_session") => legacy_route("/update_name_in_session", "", || {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			update_name(store, caller.session_id, caller.player_id, header_val(req.headers().get("name")))?;
+			cors_response(StatusCode::OK, "")
+		}),
+		(&Method::POST, "/update_pop_in_session") => legacy_route("/update_pop_in_session", "", || {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			update_pop(store, catalog, caller.session_id, caller.player_id, header_val(req.headers().get("pop")))?;
+			cors_response(StatusCode::OK, "")
+		}),
+		(&Method::POST, "/heartbeat") => legacy_route("/heartbeat", "", || {
+			let caller = SessionCaller::from_headers(&req, signer)?;
+			let status = heartbeat(store, clock, config, catalog, caller.session_id, caller.player_id)?.ok_or(LobbyError::SessionNotFound)?;
+			// the body stays just the pop for the shipped client; everything else rides in headers
+			let mut resp = match status.pop {
+				Ok(placement) => {
+					println!("heartbeat for {} {}, returning {}", caller.session_id, caller.player_id, placement.pop);
+					let pings = serde_json::to_string(&placement.pings).map_err(|e| LobbyError::Internal(e.to_string()))?;
+					let mut resp = cors_response(StatusCode::OK, placement.pop)?;
+					resp.headers_mut().insert("Expected-Pings", HeaderValue::from_str(&pings).map_err(|e| LobbyError::Internal(e.to_string()))?);
+					resp
+				},
+				// nobody has reported pings yet
+				Err(_) => cors_response(StatusCode::OK, "")?,
 			};
+			resp.headers_mut().insert("Session-State", HeaderValue::from_static(status.state.as_str()));
+			Ok(resp)
+		}),
+		// the enabled pops as the array the shipped client expects, with the catalog version
+		// in a header
+		(&Method::GET, "/get_pops") => {
+			let legacy = catalog.enabled().into_iter().map(LegacyPop::from).collect::<Vec<LegacyPop>>();
+			let mut resp = cors_response(StatusCode::OK, serde_json::to_string(&legacy).unwrap())?;
+			resp.headers_mut().insert("Pop-Catalog-Version", HeaderValue::from(catalog.version));
+			Ok(resp)
+		},
+		(&Method::POST, "/add_pings_to_session") => legacy_route("/add_pings_to_session", "", || {
+			let caller = SessionCaller::from_headers(&req, signer)?;
 			let json = req.into_body().into_string();
 			println!("add_pings_to_session got {}", json);
-			let s = get_sessions();
-			match s {
-				Ok(mut sessions) => {
-					for session in &mut sessions {
-						if session.id == session_id {
-							for p in &mut session.players {
-								if p.id == player_id {
-									add_pings_to_player(p, &json);
-								}
-							}
-						}
-					}
-					write_sessions(&sessions);
-					Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?)
-				},
-				_ => {
-					Ok(Response::builder()
-					.status(StatusCode::OK)
-					.header("Access-Control-Allow-Origin","*")
-					.header("Access-Control-Allow-Headers","*")
-					.header("Vary","Origin")
-					.body(Body::from(""))?)
-				}
-			}
-		},
+			add_pings(store, caller.session_id, caller.player_id, &parse_pings(&json, catalog)?)?;
+			cors_response(StatusCode::OK, "")
+		}),
+		// typed JSON versions of the routes above
+		(_, path) if path.starts_with("/v2/") => api::handle(req, store, clock, config, signer, catalog),
 		// Catch all other requests and return a 404.
         _ => Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body(Body::from("The page you requested could not be found"))?),
     }
 }
+
+#[cfg(test)]
+mod fixtures {
+	use super::*;
+
+	/// An open public co-op session on SEA with a player for each id, all last heard from
+	/// at `now`.
+	pub(crate) fn session(id: u32, player_ids: &[u32], now: u64) -> Session {
+		Session {
+			id: id,
+			pop: "SEA".to_string(),
+			players: player_ids.iter().enumerate().map(|(index, player_id)| Player {
+				name: format!("player{}", player_id),
+				id: *player_id,
+				index: index,
+				last_heartbeat: now,
+				pops: Vec::new(),
+				ready: false,
+			}).collect(),
+			max_players: DEFAULT_MAX_PLAYERS,
+			state: SessionState::Lobby,
+			started_at: None,
+			private: false,
+			invite_code: String::new(),
+			password: None,
+			game: GameSettings::default(),
+			created_at: now,
+			pop_strategy: PopStrategy::Mean,
+			pop_challenger: None,
+		}
+	}
+}
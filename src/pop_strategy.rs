@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::config::Config;
+
+/// How the pings the players reported for one pop combine into a score for it. The pop
+/// with the lowest score wins.
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PopStrategy {
+	/// Lowest average ping.
+	Mean,
+	/// Lowest middle ping, so one outlier either way doesn't move it.
+	Median,
+	/// Lowest worst ping, so nobody is stuck with a terrible connection.
+	MinimizeMax,
+	/// Average with each ping weighted by itself: between mean and minimize-max, bad pings
+	/// count for more without deciding everything.
+	Weighted,
+}
+
+impl PopStrategy {
+	/// The score for a pop from every reported ping to it; `pings` is never empty.
+	pub(crate) fn score(&self, pings: &[u32]) -> f32 {
+		match self {
+			PopStrategy::Mean => pings.iter().map(|p| *p as u64).sum::<u64>() as f32 / pings.len() as f32,
+			PopStrategy::Median => {
+				let mut sorted = pings.to_vec();
+				sorted.sort();
+				let mid = sorted.len() / 2;
+				match sorted.len() % 2 {
+					0 => (sorted[mid - 1] as u64 + sorted[mid] as u64) as f32 / 2.0,
+					_ => sorted[mid] as f32,
+				}
+			},
+			PopStrategy::MinimizeMax => pings.iter().max().cloned().unwrap_or(0) as f32,
+			PopStrategy::Weighted => {
+				let total = pings.iter().map(|p| *p as f64).sum::<f64>();
+				match total > 0.0 {
+					true => (pings.iter().map(|p| (*p as f64) * (*p as f64)).sum::<f64>() / total) as f32,
+					false => 0.0,
+				}
+			},
+		}
+	}
+}
+
+impl FromStr for PopStrategy {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, ()> {
+		match s {
+			"mean" => Ok(PopStrategy::Mean),
+			"median" => Ok(PopStrategy::Median),
+			"minimize_max" => Ok(PopStrategy::MinimizeMax),
+			"weighted" => Ok(PopStrategy::Weighted),
+			_ => Err(()),
+		}
+	}
+}
+
+/// What sessions use unless their creator picks something else: the `pop_strategy` setting,
+/// or mean.
+pub(crate) fn default_strategy(config: &Config) -> PopStrategy {
+	config.get_parsed("pop_strategy", PopStrategy::Mean)
+}
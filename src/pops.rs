@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+struct StaticPop {
+	name: &'static str,
+	ip: &'static str,
+	region: &'static str,
+}
+
+// what the service falls back to when the `pop_catalog` setting is missing or broken
+const POPS: &[&StaticPop] = &[
+	&StaticPop{name: "HKG", ip: "151.101.77.51", region: "asia"},
+	&StaticPop{name: "IAH", ip: "151.101.181.51", region: "north_america"},
+	&StaticPop{name: "JAX", ip: "199.232.1.51", region: "north_america"},
+	&StaticPop{name: "JNB", ip: "151.101.173.51", region: "africa"},
+	&StaticPop{name: "MCI", ip: "199.232.73.51", region: "north_america"},
+	&StaticPop{name: "LCY", ip: "151.101.17.51", region: "europe"},
+	&StaticPop{name: "LON", ip: "199.232.57.51", region: "europe"},
+	&StaticPop{name: "LHR", ip: "151.101.61.51", region: "europe"},
+	&StaticPop{name: "BUR", ip: "151.101.197.51", region: "north_america"},
+	&StaticPop{name: "LGB", ip: "151.101.25.51", region: "north_america"},
+	&StaticPop{name: "MAD", ip: "151.101.133.51", region: "europe"},
+	&StaticPop{name: "MAN", ip: "199.232.53.51", region: "europe"},
+	&StaticPop{name: "MRS", ip: "199.232.81.51", region: "europe"},
+	&StaticPop{name: "MEL", ip: "151.101.81.51", region: "oceania"},
+	&StaticPop{name: "MIA", ip: "151.101.5.51", region: "north_america"},
+	&StaticPop{name: "MSP", ip: "151.101.149.51", region: "north_america"},
+	&StaticPop{name: "STP", ip: "199.232.29.51", region: "north_america"},
+	&StaticPop{name: "YUL", ip: "151.101.137.51", region: "north_america"},
+	&StaticPop{name: "BOM", ip: "151.101.153.51", region: "asia"},
+	&StaticPop{name: "LGA", ip: "199.232.37.51", region: "north_america"},
+	&StaticPop{name: "EWR", ip: "151.101.209.51", region: "north_america"},
+	&StaticPop{name: "ITM", ip: "151.101.89.51", region: "asia"},
+	&StaticPop{name: "OSL", ip: "151.101.237.51", region: "europe"},
+	&StaticPop{name: "PAO", ip: "151.101.189.51", region: "north_america"},
+	&StaticPop{name: "CDG", ip: "151.101.121.51", region: "europe"},
+	&StaticPop{name: "GIG", ip: "151.101.177.51", region: "south_america"},
+	&StaticPop{name: "SJC", ip: "151.101.41.51", region: "north_america"},
+	&StaticPop{name: "SCL", ip: "151.101.221.51", region: "south_america"},
+	&StaticPop{name: "GRU", ip: "151.101.93.51", region: "south_america"},
+	&StaticPop{name: "SEA", ip: "151.101.53.51", region: "north_america"},
+	&StaticPop{name: "SIN", ip: "151.101.9.51", region: "asia"},
+	&StaticPop{name: "STL", ip: "199.232.69.51", region: "north_america"},
+	&StaticPop{name: "BMA", ip: "151.101.85.51", region: "europe"},
+	&StaticPop{name: "SYD", ip: "151.101.29.51", region: "oceania"},
+	&StaticPop{name: "TYO", ip: "151.101.109.51", region: "asia"},
+	&StaticPop{name: "HND", ip: "151.101.229.51", region: "asia"},
+	&StaticPop{name: "YYZ", ip: "151.101.125.51", region: "north_america"},
+	&StaticPop{name: "YVR", ip: "151.101.213.51", region: "north_america"},
+	&StaticPop{name: "VIE", ip: "199.232.17.51", region: "europe"},
+];
+
+/// One pop clients can measure and sessions can be played at.
+#[derive(Clone,Serialize,Deserialize)]
+pub(crate) struct CatalogPop {
+	pub(crate) name: String,
+	pub(crate) ip: String,
+	/// Disabled pops stay known, so old clients' pings still parse, but never win.
+	#[serde(default = "enabled")]
+	pub(crate) enabled: bool,
+	#[serde(default)]
+	pub(crate) region: String,
+}
+
+fn enabled() -> bool {
+	true
+}
+
+/// The pops the service knows about. `version` goes up with every edit so clients can tell
+/// when their list is out of date; the built-in table is version 0.
+#[derive(Serialize,Deserialize)]
+pub(crate) struct Catalog {
+	pub(crate) version: u64,
+	pub(crate) pops: Vec<CatalogPop>,
+}
+
+impl Catalog {
+	fn builtin() -> Self {
+		Catalog {
+			version: 0,
+			pops: POPS.iter().map(|p| CatalogPop {
+				name: p.name.to_string(),
+				ip: p.ip.to_string(),
+				enabled: true,
+				region: p.region.to_string(),
+			}).collect(),
+		}
+	}
+
+	/// Reads the catalog from the `pop_catalog` setting, a JSON `Catalog`, so pops can be
+	/// added or switched off without a redeploy. Falls back to the built-in table if the
+	/// setting is missing or doesn't parse.
+	pub(crate) fn load(config: &Config) -> Self {
+		match config.get("pop_catalog").map(|json| serde_json::from_str::<Catalog>(&json)) {
+			Some(Ok(catalog)) => catalog,
+			Some(Err(e)) => {
+				println!("pop_catalog doesn't parse, using the built-in pops: {}", e);
+				Catalog::builtin()
+			},
+			None => Catalog::builtin(),
+		}
+	}
+
+	pub(crate) fn get(&self, name: &str) -> Option<&CatalogPop> {
+		self.pops.iter().find(|p| p.name == name)
+	}
+
+	pub(crate) fn is_enabled(&self, name: &str) -> bool {
+		self.get(name).map_or(false, |p| p.enabled)
+	}
+
+	pub(crate) fn enabled(&self) -> Vec<&CatalogPop> {
+		self.pops.iter().filter(|p| p.enabled).collect()
+	}
+}
@@ -0,0 +1,256 @@
+use core::cmp::Ordering::Equal;
+
+use crate::config::Config;
+use crate::{MatchRequest, Session, SessionState, HEARTBEAT_TIMEOUT_MS};
+
+// sessions older than this all count as equally old
+const AGE_HORIZON_MS: u64 = 5 * 60 * 1000;
+
+/// One thing that makes a session a better or worse match, scored from 0 (worst) to 1 (best).
+pub(crate) trait Scorer {
+	fn score(&self, session: &Session, wants: &MatchRequest, now: u64) -> f32;
+}
+
+/// Fuller sessions start sooner.
+pub(crate) struct FillLevel;
+
+impl Scorer for FillLevel {
+	fn score(&self, session: &Session, _: &MatchRequest, _: u64) -> f32 {
+		session.players.len() as f32 / session.max_players.max(1) as f32
+	}
+}
+
+/// A session whose players heartbeat recently is less likely to be abandoned.
+pub(crate) struct Freshness;
+
+impl Scorer for Freshness {
+	fn score(&self, session: &Session, _: &MatchRequest, now: u64) -> f32 {
+		match session.players.iter().map(|p| p.last_heartbeat).max() {
+			Some(newest) => 1.0 - now.saturating_sub(newest) as f32 / HEARTBEAT_TIMEOUT_MS as f32,
+			None => 0.0,
+		}.max(0.0)
+	}
+}
+
+/// Players who have waited longer get matched first.
+pub(crate) struct Age;
+
+impl Scorer for Age {
+	fn score(&self, session: &Session, _: &MatchRequest, now: u64) -> f32 {
+		now.saturating_sub(session.created_at).min(AGE_HORIZON_MS) as f32 / AGE_HORIZON_MS as f32
+	}
+}
+
+/// Lower latency from the joining player to the session's pop. Players who sent no pings
+/// score every session the same; a pop they didn't measure scores worst.
+pub(crate) struct Latency;
+
+impl Scorer for Latency {
+	fn score(&self, session: &Session, wants: &MatchRequest, _: u64) -> f32 {
+		match wants.expected_latency(&session.pop) {
+			Some(latency) => 1.0 - latency as f32 / wants.max_latency_ms.max(1) as f32,
+			None if wants.pings.is_empty() => 1.0,
+			None => 0.0,
+		}.max(0.0)
+	}
+}
+
+/// Picks the sessions a player should try joining. Sessions the player can't join are
+/// dropped, and the rest are ordered by the weighted sum of the scorers.
+pub(crate) struct SessionRanker {
+	scorers: Vec<(f32, Box<dyn Scorer>)>,
+}
+
+impl SessionRanker {
+	pub(crate) fn new() -> Self {
+		SessionRanker {
+			scorers: Vec::new(),
+		}
+	}
+
+	pub(crate) fn with<S: Scorer + 'static>(mut self, weight: f32, scorer: S) -> Self {
+		self.scorers.push((weight, Box::new(scorer)));
+		self
+	}
+
+	/// The built-in scorers, weighted by the `rank_weight_*` settings.
+	pub(crate) fn from_config(config: &Config) -> Self {
+		SessionRanker::new()
+			.with(config.get_parsed("rank_weight_fill", 1.0), FillLevel)
+			.with(config.get_parsed("rank_weight_latency", 1.0), Latency)
+			.with(config.get_parsed("rank_weight_freshness", 0.5), Freshness)
+			.with(config.get_parsed("rank_weight_age", 0.25), Age)
+	}
+
+	pub(crate) fn score(&self, session: &Session, wants: &MatchRequest, now: u64) -> f32 {
+		self.scorers.iter().map(|(weight, scorer)| weight * scorer.score(session, wants, now)).sum()
+	}
+
+	/// Joinable sessions, best first. Equal scores keep their order in `sessions`.
+	pub(crate) fn rank<'a>(&self, sessions: &'a [Session], wants: &MatchRequest, now: u64) -> Vec<&'a Session> {
+		let mut ranked = sessions.iter()
+			.filter(|s| joinable(s, wants))
+			.map(|s| (s, self.score(s, wants, now)))
+			.collect::<Vec<(&Session,f32)>>();
+		ranked.sort_by(|a,b| b.1.partial_cmp(&a.1).unwrap_or(Equal));
+		ranked.into_iter().map(|(s,_)| s).collect()
+	}
+}
+
+/// Whether matchmaking may put the player in `session` at all. A player who sent pings is
+/// only matched to pops they measured, which rules out sessions with no pop yet.
+pub(crate) fn joinable(session: &Session, wants: &MatchRequest) -> bool {
+	!session.private
+		&& session.password.is_none()
+		&& session.state == SessionState::Lobby
+		&& session.players.len() < session.max_players
+		&& wants.game.matches(&session.game)
+		&& (wants.pings.is_empty() || wants.expected_latency(&session.pop).map_or(false, |latency| latency <= wants.max_latency_ms))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::auth::{PasswordHash, TokenSigner};
+	use crate::clock::{Clock, FakeClock};
+	use crate::pop_strategy::PopStrategy;
+	use crate::store::{self, MemoryStore};
+	use crate::{fixtures, join_best_session, GameFilter, Pop};
+
+	const NOW: u64 = 1_000_000;
+
+	// every built-in scorer at a fixed weight, so no setting can change what the tests see
+	fn every_scorer() -> SessionRanker {
+		SessionRanker::new()
+			.with(1.0, FillLevel)
+			.with(1.0, Latency)
+			.with(0.5, Freshness)
+			.with(0.25, Age)
+	}
+
+	// a player with no preferences beyond the pings given
+	fn wants(pings: &[(&str, u32)]) -> MatchRequest {
+		MatchRequest {
+			max_players: 4,
+			pop: "SEA".to_string(),
+			pop_strategy: PopStrategy::Mean,
+			game: GameFilter {
+				mode: None,
+				map: None,
+				skill: None,
+				wad_hash: None,
+			},
+			pings: pings.iter().map(|(name, ping)| Pop {
+				name: name.to_string(),
+				ping: *ping,
+			}).collect(),
+			max_latency_ms: 150,
+		}
+	}
+
+	fn ids(ranked: Vec<&Session>) -> Vec<u32> {
+		ranked.iter().map(|s| s.id).collect()
+	}
+
+	#[test]
+	fn unjoinable_sessions_are_filtered_out() {
+		let open = fixtures::session(1, &[1], NOW);
+		let full = fixtures::session(2, &[2, 3, 4, 5], NOW);
+		let mut private = fixtures::session(3, &[6], NOW);
+		private.private = true;
+		let mut protected = fixtures::session(4, &[7], NOW);
+		protected.password = Some(PasswordHash::new(&TokenSigner::new("secret"), "hunter2", "4"));
+		let mut in_game = fixtures::session(5, &[8], NOW);
+		in_game.state = SessionState::InGame;
+		let sessions = vec![open, full, private, protected, in_game];
+		assert_eq!(ids(SessionRanker::new().rank(&sessions, &wants(&[]), NOW)), vec![1]);
+	}
+
+	#[test]
+	fn players_who_pinged_only_get_pops_they_measured_in_range() {
+		let near = fixtures::session(1, &[1], NOW);
+		let mut far = fixtures::session(2, &[2], NOW);
+		far.pop = "LHR".to_string();
+		let mut unmeasured = fixtures::session(3, &[3], NOW);
+		unmeasured.pop = "SYD".to_string();
+		let mut no_pop = fixtures::session(4, &[4], NOW);
+		no_pop.pop = String::new();
+		let sessions = vec![near, far, unmeasured, no_pop];
+		let ranked = SessionRanker::new().rank(&sessions, &wants(&[("SEA", 40), ("LHR", 180)]), NOW);
+		assert_eq!(ids(ranked), vec![1]);
+		// without pings every pop will do
+		assert_eq!(ids(SessionRanker::new().rank(&sessions, &wants(&[]), NOW)), vec![1, 2, 3, 4]);
+	}
+
+	#[test]
+	fn fuller_sessions_rank_first() {
+		let sessions = vec![fixtures::session(1, &[1], NOW), fixtures::session(2, &[2, 3, 4], NOW)];
+		let ranked = SessionRanker::new().with(1.0, FillLevel).rank(&sessions, &wants(&[]), NOW);
+		assert_eq!(ids(ranked), vec![2, 1]);
+	}
+
+	#[test]
+	fn fresher_sessions_rank_first() {
+		let quiet = fixtures::session(1, &[1], NOW - HEARTBEAT_TIMEOUT_MS / 2);
+		let lively = fixtures::session(2, &[2], NOW);
+		let sessions = vec![quiet, lively];
+		let ranked = SessionRanker::new().with(1.0, Freshness).rank(&sessions, &wants(&[]), NOW);
+		assert_eq!(ids(ranked), vec![2, 1]);
+	}
+
+	#[test]
+	fn older_sessions_rank_first() {
+		let new = fixtures::session(1, &[1], NOW);
+		let mut old = fixtures::session(2, &[2], NOW);
+		old.created_at = NOW - AGE_HORIZON_MS / 2;
+		let sessions = vec![new, old];
+		let ranked = SessionRanker::new().with(1.0, Age).rank(&sessions, &wants(&[]), NOW);
+		assert_eq!(ids(ranked), vec![2, 1]);
+	}
+
+	#[test]
+	fn lower_latency_ranks_first() {
+		let seattle = fixtures::session(1, &[1], NOW);
+		let mut london = fixtures::session(2, &[2], NOW);
+		london.pop = "LHR".to_string();
+		let sessions = vec![seattle, london];
+		let ranked = SessionRanker::new().with(1.0, Latency).rank(&sessions, &wants(&[("SEA", 90), ("LHR", 20)]), NOW);
+		assert_eq!(ids(ranked), vec![2, 1]);
+	}
+
+	#[test]
+	fn equal_scores_keep_their_input_order() {
+		let sessions = vec![fixtures::session(3, &[3], NOW), fixtures::session(1, &[1], NOW), fixtures::session(2, &[2], NOW)];
+		let ranked = every_scorer().rank(&sessions, &wants(&[]), NOW);
+		assert_eq!(ids(ranked), vec![3, 1, 2]);
+	}
+
+	#[test]
+	fn join_best_session_creates_a_session_when_none_is_joinable() {
+		let store = MemoryStore::new();
+		let clock = FakeClock(NOW);
+		let full = store::insert_session(&store, |id| Ok(fixtures::session(id, &[1, 2, 3, 4], clock.now_millis()))).unwrap();
+		let (id, index, pop) = join_best_session(&store, &clock, &TokenSigner::new("secret"), &every_scorer(), 9, "newcomer", &wants(&[])).unwrap();
+		assert_ne!(id, full);
+		assert_eq!((index, pop.as_str()), (0, "SEA"));
+		let created = store::get_session(&store, id).unwrap().unwrap();
+		assert_eq!(created.players.iter().map(|p| p.id).collect::<Vec<u32>>(), vec![9]);
+		assert_eq!(store::get_session_ids(&store).unwrap().len(), 2);
+	}
+
+	#[test]
+	fn join_best_session_leaves_a_finished_session_behind() {
+		let store = MemoryStore::new();
+		let clock = FakeClock(NOW);
+		let finished = store::insert_session(&store, |id| {
+			let mut session = fixtures::session(id, &[1, 9], clock.now_millis());
+			session.state = SessionState::Finished;
+			Ok(session)
+		}).unwrap();
+		let open = store::insert_session(&store, |id| Ok(fixtures::session(id, &[2], clock.now_millis()))).unwrap();
+		let (id, index, _) = join_best_session(&store, &clock, &TokenSigner::new("secret"), &SessionRanker::new(), 9, "player9", &wants(&[])).unwrap();
+		assert_eq!((id, index), (open, 1));
+		let left = store::get_session(&store, finished).unwrap().unwrap();
+		assert_eq!(left.players.iter().map(|p| p.id).collect::<Vec<u32>>(), vec![1]);
+	}
+}
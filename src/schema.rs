@@ -0,0 +1,260 @@
+use fastly::Error;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::Session;
+
+/// Upgrades the data of a document by exactly one schema version.
+type Migration = fn(Value) -> Result<Value, Error>;
+
+/// One kind of stored document. Each kind is versioned on its own, so a change to sessions
+/// doesn't rewrite the index or counters under instances still running the previous build.
+/// Entry n of `migrations` upgrades version n+1 to version n+2, and the version written is
+/// one past the last of them: adding a migration is all it takes to bump a kind.
+struct Kind {
+	name: &'static str,
+	migrations: &'static [Migration],
+}
+
+impl Kind {
+	fn version(&self) -> u64 {
+		self.migrations.len() as u64 + 1
+	}
+}
+
+const SESSION: Kind = Kind {
+	name: "session",
+	migrations: &[
+		unwrapped_v1,
+		max_players_v2,
+		state_v3,
+		ready_v4,
+		invite_v5,
+		password_v6,
+		game_v7,
+		created_at_v8,
+		pop_strategy_v9,
+		pop_challenger_v10,
+		challenger_since_v11,
+	],
+};
+const INDEX: Kind = Kind {
+	name: "index",
+	migrations: &[unwrapped_v1],
+};
+// the counter was added with the envelope, so there are no v1 counters to upgrade
+const COUNTER: Kind = Kind {
+	name: "counter",
+	migrations: &[unwrapped_v1],
+};
+// an invite code's claim on a session; new with the per-kind versions
+const INVITE: Kind = Kind {
+	name: "invite",
+	migrations: &[],
+};
+
+// a session document's fields; every version so far is an object
+fn fields(data: &mut Value, version: u64) -> Result<&mut Map<String, Value>, Error> {
+	data.as_object_mut().ok_or_else(|| Error::msg(format!("v{} session is not an object", version)))
+}
+
+// the usual migration: `field` is new, and every earlier session gets `default`
+fn add_field(mut data: Value, version: u64, field: &str, default: Value) -> Result<Value, Error> {
+	fields(&mut data, version)?.entry(field).or_insert(default);
+	Ok(data)
+}
+
+// v1 documents were the bare struct with no envelope; the data itself is unchanged
+fn unwrapped_v1(data: Value) -> Result<Value, Error> {
+	Ok(data)
+}
+
+// v3 added Session.max_players; every earlier session was 4 players
+fn max_players_v2(data: Value) -> Result<Value, Error> {
+	add_field(data, 2, "max_players", Value::from(4))
+}
+
+// v4 added Session.state; sessions didn't track one before, so treat them as still in the lobby
+fn state_v3(data: Value) -> Result<Value, Error> {
+	add_field(data, 3, "state", Value::from("lobby"))
+}
+
+// v5 added Player.ready and Session.started_at; nobody had readied up and nothing had started
+fn ready_v4(data: Value) -> Result<Value, Error> {
+	let mut data = add_field(data, 4, "started_at", Value::Null)?;
+	if let Some(Value::Array(players)) = fields(&mut data, 4)?.get_mut("players") {
+		for player in players {
+			if let Some(player) = player.as_object_mut() {
+				player.entry("ready").or_insert(Value::from(false));
+			}
+		}
+	}
+	Ok(data)
+}
+
+// v6 added Session.private and Session.invite_code; every earlier session was public and
+// gets no code, since it never needed one
+fn invite_v5(data: Value) -> Result<Value, Error> {
+	let data = add_field(data, 5, "private", Value::from(false))?;
+	add_field(data, 5, "invite_code", Value::from(""))
+}
+
+// v7 added Session.password; no earlier session had one
+fn password_v6(data: Value) -> Result<Value, Error> {
+	add_field(data, 6, "password", Value::Null)
+}
+
+// v8 added Session.game; every earlier session was shareware co-op from E1M1 on skill 3
+fn game_v7(data: Value) -> Result<Value, Error> {
+	add_field(data, 7, "game", serde_json::json!({
+		"mode": "coop",
+		"map": "E1M1",
+		"skill": 3,
+		"wad_hash": "",
+	}))
+}
+
+// v9 added Session.created_at; the oldest heartbeat is the best guess we have for earlier
+// sessions
+fn created_at_v8(mut data: Value) -> Result<Value, Error> {
+	let oldest = match fields(&mut data, 8)?.get("players") {
+		Some(Value::Array(players)) => players.iter().filter_map(|p| p.get("last_heartbeat").and_then(|t| t.as_u64())).min(),
+		_ => None,
+	};
+	add_field(data, 8, "created_at", Value::from(oldest.unwrap_or(0)))
+}
+
+// v10 added Session.pop_strategy; earlier sessions always used the mean
+fn pop_strategy_v9(data: Value) -> Result<Value, Error> {
+	add_field(data, 9, "pop_strategy", Value::from("mean"))
+}
+
+// v11 added Session.pop_challenger; nothing was challenging anything before
+fn pop_challenger_v10(data: Value) -> Result<Value, Error> {
+	add_field(data, 10, "pop_challenger", Value::Null)
+}
+
+// v12 times a challenger from when it started winning instead of counting heartbeats; a
+// challenger mid-count just starts its run again
+fn challenger_since_v11(mut data: Value) -> Result<Value, Error> {
+	fields(&mut data, 11)?.insert("pop_challenger".to_string(), Value::Null);
+	Ok(data)
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+	schema: u64,
+	data: &'a T,
+}
+
+fn encode<T: Serialize>(kind: &Kind, data: &T) -> Result<String, Error> {
+	Ok(serde_json::to_string(&Envelope {
+		schema: kind.version(),
+		data: data,
+	})?)
+}
+
+fn decode<T: DeserializeOwned>(kind: &Kind, json: &str) -> Result<T, Error> {
+	let doc: Value = serde_json::from_str(json)?;
+	let (mut version, mut data) = match doc {
+		Value::Object(mut fields) if fields.contains_key("schema") => {
+			let version = match fields.get("schema").and_then(|v| v.as_u64()) {
+				Some(version) => version,
+				None => return Err(Error::msg("schema version is not a number")),
+			};
+			match fields.remove("data") {
+				Some(data) => (version, data),
+				None => return Err(Error::msg("versioned document has no data")),
+			}
+		},
+		data => (1, data),
+	};
+	if version == 0 || version > kind.version() {
+		return Err(Error::msg(format!("unsupported {} schema version {}", kind.name, version)));
+	}
+	while version < kind.version() {
+		let migrate = kind.migrations.get((version - 1) as usize)
+			.ok_or_else(|| Error::msg(format!("no migration for {} schema version {}", kind.name, version)))?;
+		data = migrate(data)?;
+		version += 1;
+	}
+	Ok(serde_json::from_value(data)?)
+}
+
+pub(crate) fn encode_session(session: &Session) -> Result<String, Error> {
+	encode(&SESSION, session)
+}
+
+pub(crate) fn decode_session(json: &str) -> Result<Session, Error> {
+	decode(&SESSION, json)
+}
+
+pub(crate) fn encode_index(ids: &[u32]) -> Result<String, Error> {
+	encode(&INDEX, &ids)
+}
+
+pub(crate) fn decode_index(json: &str) -> Result<Vec<u32>, Error> {
+	decode(&INDEX, json)
+}
+
+pub(crate) fn encode_counter(next: u32) -> Result<String, Error> {
+	encode(&COUNTER, &next)
+}
+
+pub(crate) fn decode_counter(json: &str) -> Result<u32, Error> {
+	decode(&COUNTER, json)
+}
+
+pub(crate) fn encode_invite(session_id: u32) -> Result<String, Error> {
+	encode(&INVITE, &session_id)
+}
+
+pub(crate) fn decode_invite(json: &str) -> Result<u32, Error> {
+	decode(&INVITE, json)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::SessionState;
+
+	#[test]
+	fn bare_v1_session_gets_every_default() {
+		let json = r#"{"id":3,"pop":"SEA","players":[{"name":"doomguy","id":7,"index":0,"last_heartbeat":1000,"pops":[{"name":"SEA","ping":20}]}]}"#;
+		let session = decode_session(json).unwrap();
+		assert_eq!((session.id, session.pop.as_str(), session.max_players), (3, "SEA", 4));
+		assert_eq!(session.state, SessionState::Lobby);
+		assert_eq!((session.private, session.invite_code.as_str()), (false, ""));
+		assert!(session.password.is_none());
+		assert_eq!((session.game.map.as_str(), session.game.skill), ("E1M1", 3));
+		assert_eq!(session.created_at, 1000);
+		assert!(session.pop_challenger.is_none());
+		assert_eq!(session.players[0].pops[0].ping, 20);
+		assert!(!session.players[0].ready);
+	}
+
+	#[test]
+	fn v11_session_drops_its_counted_challenger() {
+		let json = r#"{"schema":11,"data":{"id":5,"pop":"SEA","players":[],"max_players":8,"state":"starting","started_at":2000,"private":true,"invite_code":"ABC234","password":null,"game":{"mode":"deathmatch","map":"MAP01","skill":4,"wad_hash":"beef"},"created_at":1500,"pop_strategy":"median","pop_challenger":{"pop":"LHR","heartbeats":2}}}"#;
+		let session = decode_session(json).unwrap();
+		assert!(session.pop_challenger.is_none());
+		assert_eq!((session.max_players, session.state, session.started_at), (8, SessionState::Starting, Some(2000)));
+		assert_eq!((session.private, session.invite_code.as_str()), (true, "ABC234"));
+		assert_eq!((session.game.map.as_str(), session.created_at), ("MAP01", 1500));
+	}
+
+	#[test]
+	fn kinds_are_versioned_separately() {
+		assert!(encode_session(&crate::fixtures::session(1, &[1], 0)).unwrap().starts_with(r#"{"schema":12,"#));
+		assert_eq!(encode_index(&[1, 2]).unwrap(), r#"{"schema":2,"data":[1,2]}"#);
+		assert_eq!(decode_index("[1,2]").unwrap(), vec![1, 2]);
+		assert_eq!(decode_counter(r#"{"schema":2,"data":9}"#).unwrap(), 9);
+	}
+
+	#[test]
+	fn newer_documents_are_refused() {
+		assert!(decode_index(r#"{"schema":3,"data":[1]}"#).is_err());
+		assert!(decode_session(r#"{"schema":13,"data":{}}"#).is_err());
+	}
+}
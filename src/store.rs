@@ -0,0 +1,769 @@
+use fastly::http::{Method, StatusCode};
+use fastly::http::header::{ETAG, IF_MATCH, IF_NONE_MATCH};
+use fastly::{Body, Error, Request, RequestExt, Response};
+use serde::{Serialize,Deserialize};
+#[cfg(test)]
+use std::cell::{Cell, RefCell};
+#[cfg(test)]
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::Session;
+use crate::schema;
+use crate::clock::Clock;
+
+const KV_LOCAL: &str = "kvlocal";
+const KV_GLOBAL: &str = "kvglobal";
+const KV_LOCAL_URL: &str = "http://kv-local.vranish.dev/";
+const KV_GLOBAL_URL: &str = "http://kv-global.vranish.dev/";
+
+// how long a POP-local copy is served before going back to global
+const LOCAL_CACHE_TTL_SECS: u64 = 5;
+
+// the list of open session ids; each session lives under session_key(id)
+const INDEX_KEY: &str = "session_index";
+// the next session and player ids to hand out
+const SESSION_COUNTER_KEY: &str = "next_session_id";
+const PLAYER_COUNTER_KEY: &str = "next_player_id";
+
+// how many times a read-modify-write is re-run after losing a write race
+const MAX_UPDATE_ATTEMPTS: u32 = 10;
+
+/// Version of a stored value, used to make writes conditional.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Revision {
+	/// Nothing is stored under the key.
+	Absent,
+	/// Opaque tag (an ETag for the KV backend) identifying what was read.
+	Tag(String),
+}
+
+pub(crate) enum WriteResult {
+	Written,
+	/// Someone else wrote the key after we read it.
+	Conflict,
+}
+
+/// Key/value storage for lobby state. The lobby logic only talks to this trait, so it can
+/// run against the edge KV service, files on disk or plain memory.
+pub(crate) trait SessionStore {
+	fn get(&self, key: &str) -> Result<(Option<String>, Revision), Error>;
+	/// Writes `value` only if the key is still at `revision`.
+	fn put(&self, key: &str, value: &str, revision: &Revision) -> Result<WriteResult, Error>;
+	/// Removes the key only if it is still at `revision`.
+	fn delete(&self, key: &str, revision: &Revision) -> Result<WriteResult, Error>;
+
+	/// Possibly stale read for listing; anything that writes back must use `get`.
+	fn get_cached(&self, key: &str) -> Result<Option<String>, Error> {
+		Ok(self.get(key)?.0)
+	}
+}
+
+/// Picks the store from the `LOBBY_STORE` environment variable: `file:<dir>` for local
+/// runs, or the global KV backend behind the POP-local cache when unset. `clock` ages the
+/// cache.
+pub(crate) fn open<'a>(clock: &'a dyn Clock) -> Box<dyn SessionStore + 'a> {
+	let config = env::var("LOBBY_STORE").unwrap_or_default();
+	if config.starts_with("file:") {
+		Box::new(FileStore::new(&config["file:".len()..]))
+	} else {
+		Box::new(CachedStore::new(KvStore::local(), KvStore::global(), Duration::from_secs(LOCAL_CACHE_TTL_SECS), clock))
+	}
+}
+
+fn session_key(id: u32) -> String {
+	format!("session/{}", id)
+}
+
+// the session an invite code belongs to
+fn invite_key(code: &str) -> String {
+	format!("invite/{}", code)
+}
+
+// an unparseable document is an error, never an empty lobby
+fn parse_session(json: &Option<String>) -> Result<Option<Session>, Error> {
+	match json {
+		Some(json) => Ok(Some(schema::decode_session(json)?)),
+		None => Ok(None),
+	}
+}
+
+fn parse_index(json: &Option<String>) -> Result<Vec<u32>, Error> {
+	match json {
+		Some(json) => schema::decode_index(json),
+		None => Ok(Vec::new()),
+	}
+}
+
+fn get_next_id(ids: &[u32]) -> u32 {
+	let mut highest = 0;
+	for id in ids {
+		if *id > highest {
+			highest = *id;
+		}
+	}
+	highest + 1
+}
+
+pub(crate) fn get_session_ids(store: &dyn SessionStore) -> Result<Vec<u32>, Error> {
+	parse_index(&store.get_cached(INDEX_KEY)?)
+}
+
+fn update_index<T, F>(store: &dyn SessionStore, mut mutate: F) -> Result<T, Error>
+where F: FnMut(&mut Vec<u32>) -> T {
+	for attempt in 0..MAX_UPDATE_ATTEMPTS {
+		let (json, revision) = store.get(INDEX_KEY)?;
+		let mut ids = parse_index(&json)?;
+		let result = mutate(&mut ids);
+		match store.put(INDEX_KEY, &schema::encode_index(&ids)?, &revision)? {
+			WriteResult::Written => return Ok(result),
+			WriteResult::Conflict => {
+				println!("update_index: conflict on attempt {}, retrying", attempt);
+			}
+		}
+	}
+	Err(Error::msg("gave up updating session index after repeated conflicts"))
+}
+
+pub(crate) fn get_session(store: &dyn SessionStore, id: u32) -> Result<Option<Session>, Error> {
+	parse_session(&store.get_cached(&session_key(id))?)
+}
+
+/// Every session in the index. Ids whose session has already gone are skipped, and so are
+/// sessions that can't be read or decoded, so one bad document doesn't take the whole lobby
+/// down; `get_session` still reports those.
+pub(crate) fn get_sessions(store: &dyn SessionStore) -> Result<Vec<Session>, Error> {
+	let mut sessions = Vec::new();
+	for id in get_session_ids(store)? {
+		match get_session(store, id) {
+			Ok(Some(session)) => sessions.push(session),
+			Ok(None) => {},
+			Err(e) => println!("get_sessions: skipping session {}: {}", id, e),
+		}
+	}
+	Ok(sessions)
+}
+
+// ids come from a counter that only ever goes up, so an id is never handed out twice, even
+// after whatever it named is gone. `first` gives the id to start from when there's no counter.
+fn allocate_id<F>(store: &dyn SessionStore, key: &str, mut first: F) -> Result<u32, Error>
+where F: FnMut() -> Result<u32, Error> {
+	for attempt in 0..MAX_UPDATE_ATTEMPTS {
+		let (json, revision) = store.get(key)?;
+		let id = match &json {
+			Some(json) => schema::decode_counter(json)?,
+			None => first()?,
+		};
+		let next = match id.checked_add(1) {
+			Some(next) => next,
+			None => return Err(Error::msg(format!("ran out of ids for {}", key))),
+		};
+		match store.put(key, &schema::encode_counter(next)?, &revision)? {
+			WriteResult::Written => return Ok(id),
+			WriteResult::Conflict => {
+				println!("allocate_id {}: conflict on attempt {}, retrying", key, attempt);
+			}
+		}
+	}
+	Err(Error::msg(format!("gave up allocating from {} after repeated conflicts", key)))
+}
+
+fn allocate_session_id(store: &dyn SessionStore) -> Result<u32, Error> {
+	// start past any sessions that are already open
+	allocate_id(store, SESSION_COUNTER_KEY, || Ok(get_next_id(&parse_index(&store.get(INDEX_KEY)?.0)?)))
+}
+
+pub(crate) fn allocate_player_id(store: &dyn SessionStore) -> Result<u32, Error> {
+	allocate_id(store, PLAYER_COUNTER_KEY, || Ok(1))
+}
+
+/// Stores a new session under a freshly allocated id and adds it to the index. `make`
+/// builds the session for the id it is given and may be called more than once.
+pub(crate) fn insert_session<F>(store: &dyn SessionStore, mut make: F) -> Result<u32, Error>
+where F: FnMut(u32) -> Result<Session, Error> {
+	for _ in 0..MAX_UPDATE_ATTEMPTS {
+		let id = allocate_session_id(store)?;
+		let json = schema::encode_session(&make(id)?)?;
+		match store.put(&session_key(id), &json, &Revision::Absent)? {
+			WriteResult::Written => {
+				update_index(store, |ids| {
+					if !ids.contains(&id) {
+						ids.push(id);
+					}
+				})?;
+				return Ok(id);
+			},
+			WriteResult::Conflict => {
+				println!("insert_session: id {} already has a session, allocating another", id);
+			}
+		}
+	}
+	Err(Error::msg("gave up finding a free session id"))
+}
+
+/// Claims `code` for session `session_id`. The claim only succeeds if no other session
+/// holds the code, so two sessions can never share one; false means try another code.
+pub(crate) fn claim_invite_code(store: &dyn SessionStore, code: &str, session_id: u32) -> Result<bool, Error> {
+	match store.put(&invite_key(code), &schema::encode_invite(session_id)?, &Revision::Absent)? {
+		WriteResult::Written => Ok(true),
+		WriteResult::Conflict => Ok(false),
+	}
+}
+
+/// The session that claimed `code`, if any. The session itself may have gone since.
+pub(crate) fn find_invite_code(store: &dyn SessionStore, code: &str) -> Result<Option<u32>, Error> {
+	match store.get(&invite_key(code))?.0 {
+		Some(json) => Ok(Some(schema::decode_invite(&json)?)),
+		None => Ok(None),
+	}
+}
+
+// frees the code of a session that has been deleted. The session is already gone, so a
+// failure here only leaves a claim pointing at nothing, which lookups treat as no session.
+fn release_invite_code(store: &dyn SessionStore, code: &str, session_id: u32) {
+	if code.is_empty() {
+		return;
+	}
+	let key = invite_key(code);
+	let released = store.get(&key).and_then(|(json, revision)| match json {
+		Some(json) if schema::decode_invite(&json)? == session_id => store.delete(&key, &revision).map(|_| ()),
+		_ => Ok(()),
+	});
+	if let Err(e) = released {
+		println!("release_invite_code {}: {}", session_id, e);
+	}
+}
+
+/// Read-modify-write of a single session, re-run against a fresh read whenever another
+/// writer got in first. Returns `None` if the session doesn't exist. A session left with
+/// no players is deleted, dropped from the index and gives up its invite code.
+pub(crate) fn update_session<T, F>(store: &dyn SessionStore, id: u32, mut mutate: F) -> Result<Option<T>, Error>
+where F: FnMut(&mut Session) -> T {
+	try_update_session(store, id, |session| Ok(mutate(session)))
+}
+
+/// `update_session` for mutations that can turn the request down. When `mutate` returns an
+/// error nothing is written and the error is handed back; a session it left unchanged isn't
+/// written either, so a rejected or no-op request doesn't bump the revision under anyone.
+pub(crate) fn try_update_session<T, E, F>(store: &dyn SessionStore, id: u32, mut mutate: F) -> Result<Option<T>, E>
+where E: From<Error>, F: FnMut(&mut Session) -> Result<T, E> {
+	let key = session_key(id);
+	for attempt in 0..MAX_UPDATE_ATTEMPTS {
+		let (json, revision) = store.get(&key)?;
+		let mut session = match parse_session(&json)? {
+			Some(session) => session,
+			None => return Ok(None),
+		};
+		let before = schema::encode_session(&session)?;
+		let result = mutate(&mut session)?;
+		let after = schema::encode_session(&session)?;
+		if after == before {
+			return Ok(Some(result));
+		}
+		let empty = session.players.is_empty();
+		let written = match empty {
+			true => store.delete(&key, &revision)?,
+			false => store.put(&key, &after, &revision)?,
+		};
+		match written {
+			WriteResult::Written => {
+				if empty {
+					update_index(store, |ids| ids.retain(|i| *i != id))?;
+					release_invite_code(store, &session.invite_code, id);
+				}
+				return Ok(Some(result));
+			},
+			WriteResult::Conflict => {
+				println!("update_session {}: conflict on attempt {}, retrying", id, attempt);
+			}
+		}
+	}
+	Err(Error::msg(format!("gave up updating session {} after repeated conflicts", id)).into())
+}
+
+/// Values stored on an HTTP key/value backend, one URL per key. Writes carry `If-Match`
+/// with the ETag from the last read (or `If-None-Match: *` for a new key) and the backend
+/// answers `412 Precondition Failed` when that version is stale.
+pub(crate) struct KvStore {
+	backend: String,
+	base_url: String,
+}
+
+impl KvStore {
+	pub(crate) fn new(backend: &str, base_url: &str) -> Self {
+		KvStore {
+			backend: backend.to_string(),
+			base_url: base_url.to_string(),
+		}
+	}
+
+	pub(crate) fn global() -> Self {
+		KvStore::new(KV_GLOBAL, KV_GLOBAL_URL)
+	}
+
+	pub(crate) fn local() -> Self {
+		KvStore::new(KV_LOCAL, KV_LOCAL_URL)
+	}
+
+	// None when the key doesn't exist
+	fn read(&self, key: &str) -> Result<Option<Response<Body>>, Error> {
+		let kvreq = Request::builder()
+		.method(Method::GET)
+		.uri(format!("{}{}", self.base_url, key))
+		.body(Body::from(""))?;
+		let resp = kvreq.send(&self.backend)?;
+		if resp.status() == StatusCode::NOT_FOUND {
+			return Ok(None);
+		}
+		if !resp.status().is_success() {
+			return Err(Error::msg(format!("kv read of {} failed with {}", key, resp.status())));
+		}
+		Ok(Some(resp))
+	}
+
+	// unconditional when there's no revision to check against
+	fn write(&self, method: Method, key: &str, body: Body, revision: Option<&Revision>) -> Result<WriteResult, Error> {
+		let builder = Request::builder()
+		.method(method)
+		.uri(format!("{}{}", self.base_url, key));
+		let builder = match revision {
+			Some(Revision::Absent) => builder.header(IF_NONE_MATCH, "*"),
+			Some(Revision::Tag(etag)) => builder.header(IF_MATCH, etag.as_str()),
+			None => builder,
+		};
+		let resp = builder.body(body)?.send(&self.backend)?;
+		if resp.status() == StatusCode::PRECONDITION_FAILED {
+			return Ok(WriteResult::Conflict);
+		}
+		if !resp.status().is_success() {
+			return Err(Error::msg(format!("kv write of {} failed with {}", key, resp.status())));
+		}
+		Ok(WriteResult::Written)
+	}
+}
+
+impl SessionStore for KvStore {
+	fn get(&self, key: &str) -> Result<(Option<String>, Revision), Error> {
+		let resp = match self.read(key)? {
+			Some(resp) => resp,
+			None => return Ok((None, Revision::Absent)),
+		};
+		let revision = match resp.headers().get(ETAG) {
+			Some(etag) => Revision::Tag(etag.to_str()?.to_string()),
+			None => return Err(Error::msg(format!("kv read of {} returned no ETag", key))),
+		};
+		Ok((Some(resp.into_body().into_string()), revision))
+	}
+
+	fn put(&self, key: &str, value: &str, revision: &Revision) -> Result<WriteResult, Error> {
+		self.write(Method::POST, key, Body::from(value), Some(revision))
+	}
+
+	fn delete(&self, key: &str, revision: &Revision) -> Result<WriteResult, Error> {
+		self.write(Method::DELETE, key, Body::from(""), Some(revision))
+	}
+}
+
+// what the local cache keeps for each key; value is None when the key was absent
+#[derive(Serialize,Deserialize)]
+struct CacheEntry {
+	stored_at: u64,
+	value: Option<String>,
+}
+
+/// Two-tier store: the global KV backend is the source of truth, and a POP-local KV backend
+/// holds short-lived copies for `get_cached`. Writes go through to global and drop the
+/// local copy, so other POPs may read data up to `ttl` old.
+pub(crate) struct CachedStore<'a> {
+	local: KvStore,
+	global: KvStore,
+	ttl: Duration,
+	clock: &'a dyn Clock,
+}
+
+impl<'a> CachedStore<'a> {
+	pub(crate) fn new(local: KvStore, global: KvStore, ttl: Duration, clock: &'a dyn Clock) -> Self {
+		CachedStore {
+			local: local,
+			global: global,
+			ttl: ttl,
+			clock: clock,
+		}
+	}
+
+	fn read_local(&self, key: &str) -> Result<Option<CacheEntry>, Error> {
+		match self.local.read(key)? {
+			Some(resp) => Ok(serde_json::from_str(&resp.into_body().into_string()).ok()),
+			None => Ok(None),
+		}
+	}
+
+	// a broken local cache only costs us a trip to global, so failures are just logged
+	fn invalidate(&self, key: &str) {
+		if let Err(e) = self.local.write(Method::DELETE, key, Body::from(""), None) {
+			println!("CachedStore: couldn't invalidate {}: {}", key, e);
+		}
+	}
+}
+
+impl<'a> SessionStore for CachedStore<'a> {
+	fn get(&self, key: &str) -> Result<(Option<String>, Revision), Error> {
+		self.global.get(key)
+	}
+
+	fn get_cached(&self, key: &str) -> Result<Option<String>, Error> {
+		let now = self.clock.now_millis();
+		match self.read_local(key) {
+			Ok(Some(entry)) if now.saturating_sub(entry.stored_at) < self.ttl.as_millis() as u64 => {
+				return Ok(entry.value);
+			},
+			Err(e) => println!("CachedStore: local read of {} failed: {}", key, e),
+			_ => {}
+		}
+		let (value, _) = self.global.get(key)?;
+		let entry = CacheEntry {
+			stored_at: now,
+			value: value,
+		};
+		let json = serde_json::to_string(&entry)?;
+		if let Err(e) = self.local.write(Method::POST, key, Body::from(json), None) {
+			println!("CachedStore: couldn't cache {}: {}", key, e);
+		}
+		Ok(entry.value)
+	}
+
+	fn put(&self, key: &str, value: &str, revision: &Revision) -> Result<WriteResult, Error> {
+		let written = self.global.put(key, value, revision)?;
+		self.invalidate(key);
+		Ok(written)
+	}
+
+	fn delete(&self, key: &str, revision: &Revision) -> Result<WriteResult, Error> {
+		let written = self.global.delete(key, revision)?;
+		self.invalidate(key);
+		Ok(written)
+	}
+}
+
+/// Values kept in memory for the lifetime of the store. Each request gets a new store, so
+/// this is only any use to tests.
+#[cfg(test)]
+pub(crate) struct MemoryStore {
+	values: RefCell<HashMap<String, (String, u64)>>,
+	// bumped on every write and used as the revision of what was written
+	version: Cell<u64>,
+}
+
+#[cfg(test)]
+impl MemoryStore {
+	pub(crate) fn new() -> Self {
+		MemoryStore {
+			values: RefCell::new(HashMap::new()),
+			version: Cell::new(0),
+		}
+	}
+
+	fn revision(&self, key: &str) -> Revision {
+		match self.values.borrow().get(key) {
+			Some((_, v)) => Revision::Tag(v.to_string()),
+			None => Revision::Absent,
+		}
+	}
+}
+
+#[cfg(test)]
+impl SessionStore for MemoryStore {
+	fn get(&self, key: &str) -> Result<(Option<String>, Revision), Error> {
+		let value = self.values.borrow().get(key).map(|(json, _)| json.clone());
+		Ok((value, self.revision(key)))
+	}
+
+	fn put(&self, key: &str, value: &str, revision: &Revision) -> Result<WriteResult, Error> {
+		if *revision != self.revision(key) {
+			return Ok(WriteResult::Conflict);
+		}
+		self.version.set(self.version.get() + 1);
+		self.values.borrow_mut().insert(key.to_string(), (value.to_string(), self.version.get()));
+		Ok(WriteResult::Written)
+	}
+
+	fn delete(&self, key: &str, revision: &Revision) -> Result<WriteResult, Error> {
+		if *revision != self.revision(key) {
+			return Ok(WriteResult::Conflict);
+		}
+		self.values.borrow_mut().remove(key);
+		Ok(WriteResult::Written)
+	}
+}
+
+/// Values stored as files under a local directory, one file per key. A missing file means
+/// the key is absent, and the revision is a hash of the file contents.
+pub(crate) struct FileStore {
+	dir: PathBuf,
+}
+
+impl FileStore {
+	pub(crate) fn new<P: Into<PathBuf>>(dir: P) -> Self {
+		FileStore {
+			dir: dir.into(),
+		}
+	}
+
+	fn read(&self, key: &str) -> Result<Option<String>, Error> {
+		match fs::read_to_string(self.dir.join(key)) {
+			Ok(json) => Ok(Some(json)),
+			Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e.into()),
+		}
+	}
+}
+
+fn content_revision(contents: &Option<String>) -> Revision {
+	match contents {
+		Some(json) => {
+			let mut hasher = DefaultHasher::new();
+			json.hash(&mut hasher);
+			Revision::Tag(format!("{:x}", hasher.finish()))
+		},
+		None => Revision::Absent,
+	}
+}
+
+impl SessionStore for FileStore {
+	fn get(&self, key: &str) -> Result<(Option<String>, Revision), Error> {
+		let contents = self.read(key)?;
+		let revision = content_revision(&contents);
+		Ok((contents, revision))
+	}
+
+	fn put(&self, key: &str, value: &str, revision: &Revision) -> Result<WriteResult, Error> {
+		if *revision != content_revision(&self.read(key)?) {
+			return Ok(WriteResult::Conflict);
+		}
+		let path = self.dir.join(key);
+		if let Some(parent) = path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		fs::write(path, value)?;
+		Ok(WriteResult::Written)
+	}
+
+	fn delete(&self, key: &str, revision: &Revision) -> Result<WriteResult, Error> {
+		if *revision != content_revision(&self.read(key)?) {
+			return Ok(WriteResult::Conflict);
+		}
+		match fs::remove_file(self.dir.join(key)) {
+			Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+			_ => Ok(WriteResult::Written),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures;
+
+	// a MemoryStore where someone else writes `key` just before our next put to it
+	struct RacingStore {
+		inner: MemoryStore,
+		race: RefCell<Option<(String, String)>>,
+	}
+
+	impl SessionStore for RacingStore {
+		fn get(&self, key: &str) -> Result<(Option<String>, Revision), Error> {
+			self.inner.get(key)
+		}
+
+		fn put(&self, key: &str, value: &str, revision: &Revision) -> Result<WriteResult, Error> {
+			let race = self.race.borrow_mut().take();
+			match race {
+				Some((race_key, other)) if race_key == key => {
+					let (_, current) = self.inner.get(key)?;
+					self.inner.put(key, &other, &current)?;
+				},
+				race => *self.race.borrow_mut() = race,
+			}
+			self.inner.put(key, value, revision)
+		}
+
+		fn delete(&self, key: &str, revision: &Revision) -> Result<WriteResult, Error> {
+			self.inner.delete(key, revision)
+		}
+	}
+
+	// loses every write
+	struct ConflictStore(MemoryStore);
+
+	impl SessionStore for ConflictStore {
+		fn get(&self, key: &str) -> Result<(Option<String>, Revision), Error> {
+			self.0.get(key)
+		}
+
+		fn put(&self, _: &str, _: &str, _: &Revision) -> Result<WriteResult, Error> {
+			Ok(WriteResult::Conflict)
+		}
+
+		fn delete(&self, _: &str, _: &Revision) -> Result<WriteResult, Error> {
+			Ok(WriteResult::Conflict)
+		}
+	}
+
+	fn insert(store: &dyn SessionStore, player_ids: &[u32]) -> u32 {
+		insert_session(store, |id| Ok(fixtures::session(id, player_ids, 1000))).unwrap()
+	}
+
+	#[test]
+	fn memory_store_rejects_stale_revisions() {
+		let store = MemoryStore::new();
+		assert!(matches!(store.put("k", "a", &Revision::Absent).unwrap(), WriteResult::Written));
+		assert!(matches!(store.put("k", "b", &Revision::Absent).unwrap(), WriteResult::Conflict));
+		let (value, revision) = store.get("k").unwrap();
+		assert_eq!(value.as_deref(), Some("a"));
+		assert!(matches!(store.put("k", "b", &revision).unwrap(), WriteResult::Written));
+		assert!(matches!(store.delete("k", &revision).unwrap(), WriteResult::Conflict));
+	}
+
+	#[test]
+	fn player_ids_count_up_from_one() {
+		let store = MemoryStore::new();
+		let ids = (0..3).map(|_| allocate_player_id(&store).unwrap()).collect::<Vec<u32>>();
+		assert_eq!(ids, vec![1, 2, 3]);
+	}
+
+	#[test]
+	fn allocate_id_retries_after_conflict() {
+		let store = RacingStore {
+			inner: MemoryStore::new(),
+			race: RefCell::new(Some((PLAYER_COUNTER_KEY.to_string(), schema::encode_counter(5).unwrap()))),
+		};
+		// the racing writer took ids up to 4, so we get 5
+		assert_eq!(allocate_player_id(&store).unwrap(), 5);
+		assert_eq!(allocate_player_id(&store).unwrap(), 6);
+	}
+
+	#[test]
+	fn allocate_id_gives_up_after_repeated_conflicts() {
+		assert!(allocate_player_id(&ConflictStore(MemoryStore::new())).is_err());
+	}
+
+	#[test]
+	fn session_ids_start_past_the_index() {
+		let store = MemoryStore::new();
+		store.put(INDEX_KEY, &schema::encode_index(&[4, 7]).unwrap(), &Revision::Absent).unwrap();
+		assert_eq!(insert(&store, &[1]), 8);
+		assert_eq!(insert(&store, &[2]), 9);
+	}
+
+	#[test]
+	fn insert_session_stores_and_indexes() {
+		let store = MemoryStore::new();
+		let id = insert(&store, &[1, 2]);
+		assert_eq!(get_session_ids(&store).unwrap(), vec![id]);
+		let session = get_session(&store, id).unwrap().unwrap();
+		assert_eq!(session.id, id);
+		assert_eq!(session.players.iter().map(|p| p.id).collect::<Vec<u32>>(), vec![1, 2]);
+	}
+
+	#[test]
+	fn listing_skips_sessions_that_dont_decode() {
+		let store = MemoryStore::new();
+		let good = insert(&store, &[1]);
+		let bad = insert(&store, &[2]);
+		let (_, revision) = store.get(&session_key(bad)).unwrap();
+		store.put(&session_key(bad), "{\"schema\":99,\"data\":{}}", &revision).unwrap();
+		let ids = get_sessions(&store).unwrap().iter().map(|s| s.id).collect::<Vec<u32>>();
+		assert_eq!(ids, vec![good]);
+		assert!(get_session(&store, bad).is_err());
+	}
+
+	#[test]
+	fn update_session_reruns_mutation_after_conflict() {
+		let inner = MemoryStore::new();
+		let id = insert(&inner, &[1]);
+		// someone else adds player 2 between our read and our write
+		let raced = fixtures::session(id, &[1, 2], 1000);
+		let store = RacingStore {
+			inner: inner,
+			race: RefCell::new(Some((session_key(id), schema::encode_session(&raced).unwrap()))),
+		};
+		let mut calls = 0;
+		let result = update_session(&store, id, |session| {
+			calls += 1;
+			session.players[0].name = "renamed".to_string();
+			session.players.len()
+		}).unwrap();
+		assert_eq!(calls, 2);
+		assert_eq!(result, Some(2));
+		let session = get_session(&store, id).unwrap().unwrap();
+		assert_eq!(session.players.len(), 2);
+		assert_eq!(session.players[0].name, "renamed");
+	}
+
+	#[test]
+	fn update_session_deletes_empty_sessions() {
+		let store = MemoryStore::new();
+		let id = insert(&store, &[1]);
+		update_session(&store, id, |session| session.players.clear()).unwrap();
+		assert!(get_session(&store, id).unwrap().is_none());
+		assert!(get_session_ids(&store).unwrap().is_empty());
+	}
+
+	#[test]
+	fn rejected_or_unchanged_updates_are_not_written() {
+		let store = MemoryStore::new();
+		let id = insert(&store, &[1]);
+		let (_, before) = store.get(&session_key(id)).unwrap();
+		let rejected: Result<Option<()>, Error> = try_update_session(&store, id, |session| {
+			session.players.clear();
+			Err(Error::msg("no"))
+		});
+		assert!(rejected.is_err());
+		update_session(&store, id, |_| ()).unwrap();
+		let (json, after) = store.get(&session_key(id)).unwrap();
+		assert_eq!(before, after);
+		assert_eq!(schema::decode_session(&json.unwrap()).unwrap().players.len(), 1);
+	}
+
+	#[test]
+	fn update_session_of_missing_session_is_none() {
+		let store = MemoryStore::new();
+		assert!(update_session(&store, 3, |_| ()).unwrap().is_none());
+	}
+
+	#[test]
+	fn update_session_gives_up_after_repeated_conflicts() {
+		let inner = MemoryStore::new();
+		let id = insert(&inner, &[1]);
+		assert!(update_session(&ConflictStore(inner), id, |session| session.pop = "LHR".to_string()).is_err());
+	}
+
+	#[test]
+	fn invite_codes_can_only_be_claimed_once() {
+		let store = MemoryStore::new();
+		assert!(claim_invite_code(&store, "ABCD", 1).unwrap());
+		assert!(!claim_invite_code(&store, "ABCD", 2).unwrap());
+		assert_eq!(find_invite_code(&store, "ABCD").unwrap(), Some(1));
+		assert_eq!(find_invite_code(&store, "WXYZ").unwrap(), None);
+	}
+
+	#[test]
+	fn deleted_sessions_give_up_their_invite_code() {
+		let store = MemoryStore::new();
+		let id = insert_session(&store, |id| {
+			claim_invite_code(&store, "ABCD", id)?;
+			let mut session = fixtures::session(id, &[1], 1000);
+			session.invite_code = "ABCD".to_string();
+			Ok(session)
+		}).unwrap();
+		update_session(&store, id, |session| session.players.clear()).unwrap();
+		assert_eq!(find_invite_code(&store, "ABCD").unwrap(), None);
+		assert!(claim_invite_code(&store, "ABCD", id + 1).unwrap());
+	}
+}